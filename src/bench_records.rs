@@ -7,7 +7,6 @@ pub struct LigthRecord {
 
 impl Record<usize> for LigthRecord {
     fn key(&self) -> usize {
-        println!("{:?}", self.value);
-        self.value.0.clone()
+        self.value.0
     }
 }
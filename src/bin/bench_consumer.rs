@@ -1,12 +1,10 @@
 extern crate shmtest;
 
-use shmtest::bench_records::LigthRecord;
+use shmtest::common::clock::now;
+use shmtest::common::stream::Envelope;
 use shmtest::common::stream_consumer::ShmStream;
 use shmtest::common::ShmDefinition;
-use std::{
-    mem::size_of,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::mem::size_of;
 
 use clap::{self, Parser};
 
@@ -33,39 +31,30 @@ fn main() {
 fn test_light_load(warmup_count: usize, count: usize) {
     let definition = ShmDefinition::new(
         "test_stream".to_string(),
-        size_of::<LigthRecord>() * (warmup_count + count),
+        size_of::<Envelope<usize>>() * (warmup_count + count + 1),
     );
-    let mut stream: ShmStream<LigthRecord> = ShmStream::open(definition).unwrap();
+    let mut stream: ShmStream<usize> = ShmStream::open(definition).unwrap();
 
     let mut sequence = 0;
 
     // Warmup
     while sequence < warmup_count {
-        match stream.next() {
-            Some(t) => {
-                sequence = t.value.0;
-            }
-            None => {}
+        if let Some(t) = stream.next_envelope() {
+            sequence = t.sequence as usize;
         }
     }
 
     let mut result = Vec::with_capacity(count);
     while sequence < count {
-        match stream.next() {
-            Some(t) => {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos();
+        if let Some(t) = stream.next_envelope() {
+            let now = now();
 
-                result.push((t.value.0, now, t.value.1));
-                sequence = t.value.0;
-            }
-            None => {}
+            result.push((t.sequence, now, t.timestamp));
+            sequence = t.sequence as usize;
         }
     }
 
-    let mut previous: Option<(usize, u128, u128)> = None;
+    let mut previous: Option<(u64, u64, u64)> = None;
     for r in result {
         println!(
             "{} {} {} {} {} {}",
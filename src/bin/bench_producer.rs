@@ -1,13 +1,10 @@
 extern crate shmtest;
 
 use core::ops::Add;
-use shmtest::bench_records::LigthRecord;
+use shmtest::common::stream::Envelope;
 use shmtest::common::stream_producer::ShmStream;
 use shmtest::common::ShmDefinition;
-use std::{
-    mem::size_of,
-    time::{Instant, SystemTime, UNIX_EPOCH},
-};
+use std::{mem::size_of, time::Instant};
 
 use clap::{self, Parser};
 
@@ -42,16 +39,18 @@ fn main() {
 fn run_light_load(warmup_count: usize, count: usize, beat: std::time::Duration) {
     let stream_definition = ShmDefinition::new(
         "test_stream".to_string(),
-        size_of::<LigthRecord>() * (warmup_count + count),
+        // One more slot for the header
+        size_of::<Envelope<usize>>() * (warmup_count + count + 1),
     );
-    let mut stream: ShmStream<LigthRecord> = ShmStream::open(stream_definition).unwrap();
+    // The envelope carries the sequence and the publish timestamp
+    let mut stream: ShmStream<usize> = ShmStream::open_enveloped(stream_definition, 0).unwrap();
 
     std::thread::sleep(std::time::Duration::from_secs(30));
 
     // Warmup
     for i in 0..warmup_count {
         wait(beat);
-        stream.insert(build_light_record(i)).unwrap();
+        stream.insert(i).unwrap();
     }
 
     std::thread::sleep(std::time::Duration::from_secs(5));
@@ -59,7 +58,7 @@ fn run_light_load(warmup_count: usize, count: usize, beat: std::time::Duration)
     // Warmup
     for i in warmup_count..count {
         wait(beat);
-        stream.insert(build_light_record(i)).unwrap();
+        stream.insert(i).unwrap();
     }
 }
 
@@ -70,15 +69,3 @@ fn wait(duration: std::time::Duration) {
         // burn
     }
 }
-
-fn build_light_record(seq_num: usize) -> LigthRecord {
-    LigthRecord {
-        value: (
-            seq_num + 1,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos(),
-        ),
-    }
-}
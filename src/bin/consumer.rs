@@ -1,11 +1,11 @@
 extern crate shmtest;
 
+use shmtest::common::clock::now;
 use shmtest::common::reader::ShmReader;
 use shmtest::common::store_customer::ShmStore;
 use shmtest::common::stream_consumer::ShmStream;
 use shmtest::common::{ShmDefinition, TestRecord};
 use std::io::Read;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     test_reader();
@@ -14,13 +14,13 @@ fn main() {
 }
 
 fn test_reader() {
-    let definition = ShmDefinition::new("test_writer".to_string(), 10);
+    let definition = ShmDefinition::new("test_writer".to_string(), 1024);
     let mut reader = ShmReader::open(definition).unwrap();
-    let mut buffer = vec![0 as u8; 1024];
+    let mut buffer = vec![0_u8; 1024];
 
-    reader.read(&mut buffer).unwrap();
+    let read = reader.read(&mut buffer).unwrap();
 
-    println!("{}", std::str::from_utf8(&buffer).unwrap());
+    println!("{}", std::str::from_utf8(&buffer[..read]).unwrap());
 }
 
 fn test_store_client() {
@@ -33,34 +33,23 @@ fn test_store_client() {
 
     loop {
         std::thread::sleep(std::time::Duration::from_secs(1));
-        match store.get(&4) {
-            Ok(r) => {
-                println!("Found {:?}", r.value);
-                break;
-            }
-            Err(_) => {}
+        if let Ok(r) = store.get(&4) {
+            println!("Found {:?}", r.value);
+            break;
         }
     }
 }
 
 fn test_stream_consumer() {
     let definition = ShmDefinition::new("test_stream".to_string(), 1024);
-    let mut stream: ShmStream<u128> = ShmStream::open(definition).unwrap();
+    let mut stream: ShmStream<u64> = ShmStream::open(definition).unwrap();
 
     let mut sequence = 0;
 
     while sequence < 10 {
-        match stream.next() {
-            Some(t) => {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_nanos();
-
-                println!("Lag {}", now - t);
-                sequence += 1;
-            }
-            None => {}
+        if let Some(t) = stream.next_envelope() {
+            println!("Lag {}", now() - t.timestamp);
+            sequence += 1;
         }
     }
 }
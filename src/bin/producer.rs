@@ -6,20 +6,19 @@ use shmtest::common::writer::ShmWriter;
 use shmtest::common::{ShmDefinition, TestRecord};
 
 use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
-    let writer_definition = ShmDefinition::new("test_writer".to_string(), 10);
+    let writer_definition = ShmDefinition::new("test_writer".to_string(), 1024);
     let mut writer = ShmWriter::open(writer_definition).unwrap();
 
     let store_definition = ShmDefinition::new("test_store".to_string(), 1024);
     let mut store: ShmStore<i32, TestRecord> = ShmStore::open(store_definition).unwrap();
 
     let stream_definition = ShmDefinition::new("test_stream".to_string(), 1024);
-    let mut stream: ShmStream<u128> = ShmStream::open(stream_definition).unwrap();
+    let mut stream: ShmStream<u64> = ShmStream::open_enveloped(stream_definition, 0).unwrap();
 
-    writer.write("test1".as_bytes()).unwrap();
-    writer.write("test2".as_bytes()).unwrap();
+    writer.write_all("test1".as_bytes()).unwrap();
+    writer.write_all("test2".as_bytes()).unwrap();
     writer.flush().unwrap();
 
     store.put(TestRecord { value: (1, 11) }).unwrap();
@@ -30,12 +29,8 @@ fn main() {
 
     std::thread::sleep(std::time::Duration::from_secs(30));
 
-    for _ in 0..10 {
+    for i in 0..10 {
         std::thread::sleep(std::time::Duration::from_millis(10));
-        let event = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        stream.insert(event).unwrap();
+        stream.insert(i).unwrap();
     }
 }
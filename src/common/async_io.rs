@@ -0,0 +1,216 @@
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::runtime::Handle;
+
+use crate::common::notify::ShmNotification;
+use crate::common::reader::ShmReader;
+use crate::common::stream_consumer::ShmStream;
+use crate::common::writer::ShmWriter;
+
+// Registers the notification with the reactor of the current runtime, fails outside of one.
+fn register(
+    notification: nix::Result<ShmNotification>,
+) -> std::io::Result<AsyncFd<ShmNotification>> {
+    Handle::try_current().map_err(std::io::Error::other)?;
+    AsyncFd::new(notification?)
+}
+
+// Retries the operation until it stops blocking, waiting on the notification in between.
+fn poll_notified<T>(
+    notification: &AsyncFd<ShmNotification>,
+    cx: &mut Context<'_>,
+    mut operation: impl FnMut() -> std::io::Result<T>,
+) -> Poll<std::io::Result<T>> {
+    loop {
+        match operation() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            result => return Poll::Ready(result),
+        }
+        let mut guard = ready!(notification.poll_read_ready(cx))?;
+        // Cleared before trying again so that a later publication is not missed
+        guard.get_inner().clear();
+        guard.clear_ready();
+    }
+}
+
+// Reads without blocking the runtime, woken up by the writes to the pipe.
+pub struct AsyncShmReader {
+    reader: ShmReader,
+    notification: AsyncFd<ShmNotification>,
+}
+
+impl AsyncShmReader {
+    pub fn new(reader: ShmReader) -> std::io::Result<Self> {
+        let notification = register(reader.notification())?;
+        Ok(Self {
+            reader,
+            notification,
+        })
+    }
+}
+
+impl AsyncRead for AsyncShmReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let read = ready!(poll_notified(&this.notification, cx, || this
+            .reader
+            .read_nonblocking(buf.initialize_unfilled())))?;
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Writes without blocking the runtime, woken up when the readers consume.
+pub struct AsyncShmWriter {
+    writer: ShmWriter,
+    notification: AsyncFd<ShmNotification>,
+}
+
+impl AsyncShmWriter {
+    pub fn new(writer: ShmWriter) -> std::io::Result<Self> {
+        let notification = register(writer.notification())?;
+        Ok(Self {
+            writer,
+            notification,
+        })
+    }
+}
+
+impl AsyncWrite for AsyncShmWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        poll_notified(&this.notification, cx, || {
+            this.writer.write_nonblocking(buf)
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(std::io::Write::flush(&mut self.get_mut().writer))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().writer.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Stream of the events of a consumer, woken up by the producer. It never ends.
+pub struct AsyncShmStream<E: Copy> {
+    stream: ShmStream<E>,
+    notification: AsyncFd<ShmNotification>,
+}
+
+impl<E: Copy> AsyncShmStream<E> {
+    pub fn new(stream: ShmStream<E>) -> std::io::Result<Self> {
+        let notification = register(stream.notification())?;
+        Ok(Self {
+            stream,
+            notification,
+        })
+    }
+}
+
+impl<E: Copy> Unpin for AsyncShmStream<E> {}
+
+impl<E: Copy> Stream for AsyncShmStream<E> {
+    type Item = std::io::Result<E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        poll_notified(&this.notification, cx, || {
+            this.stream
+                .try_next()
+                .ok_or_else(|| ErrorKind::WouldBlock.into())
+        })
+        .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::io::Write;
+    use std::pin::Pin;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures_core::Stream;
+    use tokio::io::AsyncReadExt;
+
+    use super::{AsyncShmReader, AsyncShmStream, AsyncShmWriter};
+    use crate::common::{
+        reader::ShmReader, stream_consumer, stream_producer, writer::ShmWriter, ShmDefinition,
+    };
+
+    #[test_log::test(tokio::test(flavor = "multi_thread"))]
+    async fn reader_is_woken_up_by_the_writer() {
+        let (opened, is_opened) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            let mut writer =
+                ShmWriter::open(ShmDefinition::new("async_pipe".to_string(), 1024)).unwrap();
+            opened.send(()).unwrap();
+            for i in 0..10_u8 {
+                thread::sleep(Duration::from_millis(5));
+                writer.write_all(&[i]).unwrap();
+            }
+        });
+
+        is_opened.recv().unwrap();
+        let mut reader = AsyncShmReader::new(
+            ShmReader::open(ShmDefinition::new("async_pipe".to_string(), 1024)).unwrap(),
+        )
+        .unwrap();
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        producer.join().unwrap();
+        assert_eq!((0..10).collect::<Vec<u8>>(), received);
+    }
+
+    #[test_log::test(tokio::test(flavor = "multi_thread"))]
+    async fn stream_is_woken_up_by_the_producer() {
+        let (opened, is_opened) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            let mut producer: stream_producer::ShmStream<u64> = stream_producer::ShmStream::open(
+                ShmDefinition::new("async_stream".to_string(), 1024),
+            )
+            .unwrap();
+            opened.send(()).unwrap();
+            for i in 0..10 {
+                thread::sleep(Duration::from_millis(5));
+                producer.insert(i).unwrap();
+            }
+        });
+
+        is_opened.recv().unwrap();
+        let consumer: stream_consumer::ShmStream<u64> =
+            stream_consumer::ShmStream::open(ShmDefinition::new("async_stream".to_string(), 1024))
+                .unwrap();
+        let mut consumer = AsyncShmStream::new(consumer).unwrap();
+        for i in 0..10 {
+            let event = poll_fn(|cx| Pin::new(&mut consumer).poll_next(cx)).await;
+            assert_eq!(Some(i), event.map(|event| event.unwrap()));
+        }
+        producer.join().unwrap();
+    }
+
+    #[test_log::test]
+    fn wrappers_need_a_runtime() {
+        let writer =
+            ShmWriter::open(ShmDefinition::new("async_no_runtime".to_string(), 1024)).unwrap();
+        assert!(AsyncShmWriter::new(writer).is_err());
+    }
+}
@@ -0,0 +1,8 @@
+use nix::time::{clock_gettime, ClockId};
+
+// Nanoseconds of CLOCK_MONOTONIC, shared by all the processes of the host
+// and unaffected by the steps of the wall clock.
+pub fn now() -> u64 {
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).unwrap();
+    now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64
+}
@@ -0,0 +1,50 @@
+use crate::common::Record;
+
+// The last N values of a key with their timestamps, stored as a single record.
+#[derive(Clone, Copy)]
+pub struct History<R, const N: usize> {
+    len: usize,
+    next: usize,
+    entries: [(u64, R); N],
+}
+
+impl<R: Copy, const N: usize> History<R, N> {
+    // Fails the build of histories that could not hold a value.
+    const NOT_EMPTY: () = assert!(N > 0, "a history holds at least one value");
+
+    pub(crate) fn new(timestamp: u64, record: R) -> Self {
+        let () = Self::NOT_EMPTY;
+        History {
+            len: 1,
+            next: 1 % N,
+            entries: [(timestamp, record); N],
+        }
+    }
+
+    // Overwrites the oldest value once the ring is full.
+    pub(crate) fn push(&mut self, timestamp: u64, record: R) {
+        self.entries[self.next] = (timestamp, record);
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn latest(&self) -> (u64, R) {
+        self.entries[(self.next + N - 1) % N]
+    }
+
+    // Newest first.
+    pub fn last(&self, count: usize) -> impl Iterator<Item = (u64, R)> + '_ {
+        (1..=self.len.min(count)).map(|i| self.entries[(self.next + N - i) % N])
+    }
+
+    // The value that was current at the timestamp.
+    pub fn as_of(&self, timestamp: u64) -> Option<(u64, R)> {
+        self.last(self.len).find(|(t, _)| *t <= timestamp)
+    }
+}
+
+impl<K, R: Record<K>, const N: usize> Record<K> for History<R, N> {
+    fn key(&self) -> K {
+        self.entries[0].1.key()
+    }
+}
@@ -0,0 +1,72 @@
+use std::hash::Hash;
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::history::History;
+use crate::common::store_customer::ShmStore;
+use crate::common::{Record, ShmDefinition};
+
+pub struct ShmHistory<K, R: Record<K>, const N: usize> {
+    store: ShmStore<K, History<R, N>>,
+}
+
+impl<K: Eq + Hash + Clone, R: Record<K>, const N: usize> ShmHistory<K, R, N> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        ShmStore::open(definition).map(|store| Self { store })
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<(u64, R)> {
+        self.store.get(key).map(|history| history.latest())
+    }
+
+    // Up to count values with their timestamps, newest first.
+    pub fn last(&mut self, key: &K, count: usize) -> Result<Vec<(u64, R)>> {
+        self.store
+            .get(key)
+            .map(|history| history.last(count).collect())
+    }
+
+    // The value of the key at the timestamp, if it is still in the history.
+    pub fn as_of(&mut self, key: &K, timestamp: u64) -> Result<(u64, R)> {
+        self.store
+            .get(key)
+            .and_then(|history| history.as_of(timestamp).ok_or(Errno::ENOKEY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::errno::Errno;
+
+    use crate::common::{history_customer::ShmHistory, history_owner, ShmDefinition, TestRecord};
+
+    #[test_log::test]
+    fn history_keeps_the_last_values_of_a_key() {
+        let mut owner: history_owner::ShmHistory<i32, TestRecord, 3> =
+            history_owner::ShmHistory::open(ShmDefinition::new("history".to_string(), 1024))
+                .unwrap();
+        for (timestamp, value) in [(10, 1), (20, 2), (30, 3), (40, 4)] {
+            owner
+                .put_at(TestRecord { value: (1, value) }, timestamp)
+                .unwrap();
+        }
+        assert_eq!(
+            Some(Errno::EINVAL),
+            owner.put_at(TestRecord { value: (1, 0) }, 35).err()
+        );
+        let mut customer: ShmHistory<i32, TestRecord, 3> =
+            ShmHistory::open(ShmDefinition::new("history".to_string(), 1024)).unwrap();
+
+        let values: Vec<(u64, i32)> = customer
+            .last(&1, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(timestamp, record)| (timestamp, record.value.1))
+            .collect();
+        assert_eq!(vec![(40, 4), (30, 3), (20, 2)], values);
+        assert_eq!(3, customer.as_of(&1, 35).unwrap().1.value.1);
+        assert_eq!(Some(Errno::ENOKEY), customer.as_of(&1, 15).err());
+        assert_eq!(40, customer.get(&1).unwrap().0);
+    }
+}
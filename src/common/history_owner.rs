@@ -0,0 +1,44 @@
+use std::hash::Hash;
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::clock::now;
+use crate::common::history::History;
+use crate::common::store_owner::ShmStore;
+use crate::common::{Record, ShmDefinition};
+
+// Store keeping the last N values of every key.
+pub struct ShmHistory<K, R: Record<K>, const N: usize> {
+    store: ShmStore<K, History<R, N>>,
+}
+
+impl<K: Eq + Hash + Clone, R: Record<K>, const N: usize> ShmHistory<K, R, N> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        ShmStore::open(definition).map(|store| Self { store })
+    }
+
+    pub fn put(&mut self, record: R) -> Result<()> {
+        self.put_at(record, now())
+    }
+
+    // Timestamps of a key can not go back in time.
+    pub fn put_at(&mut self, record: R, timestamp: u64) -> Result<()> {
+        let history = match self.store.get_versioned(&record.key()) {
+            Ok((_, mut history)) => {
+                if timestamp < history.latest().0 {
+                    return Err(Errno::EINVAL);
+                }
+                history.push(timestamp, record);
+                history
+            }
+            Err(Errno::ENOKEY) => History::new(timestamp, record),
+            Err(e) => return Err(e),
+        };
+        self.store.put(history)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        self.store.remove(key).map(|_| ())
+    }
+}
@@ -0,0 +1,293 @@
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use linux_futex::{Futex, Shared};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use nix::Result;
+
+use crate::common::pthread::{lock_robust, unlock_robust, RobustLock};
+use crate::common::shm::{MutableShmMap, ShmMap};
+use crate::common::socket::ShmSocket;
+use crate::common::ShmDefinition;
+
+// Number of connection requests waiting to be accepted.
+pub const BACKLOG: usize = 16;
+// Queued id of a request withdrawn by its client.
+const WITHDRAWN: u64 = u64::MAX;
+
+// Control segment of a listener, clients queue their connection ids in it.
+#[repr(C)]
+struct ListenerHeader {
+    pipe_size: usize,
+    // Process of the listener, clients are refused once it is gone or closed.
+    listener: AtomicI32,
+    closed: AtomicU32,
+    next_id: AtomicU64,
+    lock: RobustLock,
+    // Bumped on every request and accept to wake up the listener and the clients.
+    requested: Futex<Shared>,
+    accepted: Futex<Shared>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    queue: [AtomicU64; BACKLOG],
+    // Process of the client of each queued id.
+    clients: [AtomicI32; BACKLOG],
+}
+
+impl ListenerHeader {
+    fn is_listening(&self) -> bool {
+        let listener = Pid::from_raw(self.listener.load(Ordering::Relaxed));
+        self.closed.load(Ordering::Acquire) == 0 && kill(listener, None) != Err(Errno::ESRCH)
+    }
+}
+
+fn socket_definition(name: &str, id: u64, pipe_size: usize) -> ShmDefinition {
+    ShmDefinition::new(format!("{}.{}", name, id), pipe_size)
+}
+
+// Creates a socket for every client connecting to its name.
+pub struct ShmListener {
+    _map: MutableShmMap,
+    name: String,
+    header: *const ListenerHeader,
+}
+
+impl ShmListener {
+    // The size of the definition is the size of each pipe of the accepted sockets.
+    pub fn bind(definition: ShmDefinition) -> Result<Self> {
+        let name = definition.name.clone();
+        let pipe_size = definition.size;
+        MutableShmMap::create(ShmDefinition::new(
+            name.clone(),
+            std::mem::size_of::<ListenerHeader>(),
+        ))
+        .map(|m| {
+            let header = m.start_ptr() as *mut ListenerHeader;
+            unsafe {
+                (*header).pipe_size = pipe_size;
+                (*header)
+                    .listener
+                    .store(std::process::id() as i32, Ordering::Relaxed);
+                (*header).closed.store(0, Ordering::Relaxed);
+                (*header).next_id.store(0, Ordering::Relaxed);
+                (*header).lock.init();
+                (*header).requested.value.store(0, Ordering::Relaxed);
+                (*header).accepted.value.store(0, Ordering::Relaxed);
+                (*header).head.store(0, Ordering::Relaxed);
+                (*header).tail.store(0, Ordering::Release);
+            }
+            Self {
+                _map: m,
+                name,
+                header,
+            }
+        })
+    }
+
+    // Waits for the next connection request and creates its socket.
+    pub fn accept(&mut self) -> Result<ShmSocket<MutableShmMap>> {
+        let header = unsafe { &*self.header };
+        loop {
+            let requested = header.requested.value.load(Ordering::Acquire);
+            lock_robust(&header.lock);
+            let head = header.head.load(Ordering::Relaxed);
+            let request = (head < header.tail.load(Ordering::Relaxed)).then(|| {
+                header.head.store(head + 1, Ordering::Relaxed);
+                (
+                    header.queue[head % BACKLOG].load(Ordering::Relaxed),
+                    Pid::from_raw(header.clients[head % BACKLOG].load(Ordering::Relaxed)),
+                )
+            });
+            unlock_robust(&header.lock);
+            if let Some((id, client)) = request {
+                // Once popped the client waits for its socket, unless it died since
+                if id == WITHDRAWN || kill(client, None) == Err(Errno::ESRCH) {
+                    continue;
+                }
+                let socket = ShmSocket::create(socket_definition(&self.name, id, header.pipe_size))
+                    .map(|socket| socket.with_peer(client));
+                header.accepted.value.fetch_add(1, Ordering::Release);
+                header.accepted.wake(i32::MAX);
+                return socket;
+            }
+            let _ = header.requested.wait(requested);
+        }
+    }
+}
+
+impl Drop for ShmListener {
+    // The clients waiting for their socket are refused.
+    fn drop(&mut self) {
+        let header = unsafe { &*self.header };
+        header.closed.store(1, Ordering::Release);
+        header.accepted.value.fetch_add(1, Ordering::Release);
+        header.accepted.wake(i32::MAX);
+    }
+}
+
+// Queues a connection request to the listener bound to the name, and connects to the socket
+// it creates for it. Fails with ECONNREFUSED if the listener is gone or its backlog is full,
+// and with ETIMEDOUT if the request is not accepted before the timeout if any.
+pub fn connect(name: &str, timeout: Option<Duration>) -> Result<ShmSocket<ShmMap>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let map = ShmMap::open(ShmDefinition::new(
+        name.to_string(),
+        std::mem::size_of::<ListenerHeader>(),
+    ))?;
+    let header = unsafe { &*(map.start_ptr() as *const ListenerHeader) };
+    if !header.is_listening() {
+        return Err(Errno::ECONNREFUSED);
+    }
+    let id = header.next_id.fetch_add(1, Ordering::Relaxed);
+    lock_robust(&header.lock);
+    let tail = header.tail.load(Ordering::Relaxed);
+    let queued = tail - header.head.load(Ordering::Relaxed) < BACKLOG;
+    if queued {
+        header.queue[tail % BACKLOG].store(id, Ordering::Relaxed);
+        header.clients[tail % BACKLOG].store(std::process::id() as i32, Ordering::Relaxed);
+        header.tail.store(tail + 1, Ordering::Relaxed);
+    }
+    unlock_robust(&header.lock);
+    if !queued {
+        return Err(Errno::ECONNREFUSED);
+    }
+    header.requested.value.fetch_add(1, Ordering::Release);
+    header.requested.wake(i32::MAX);
+
+    loop {
+        let accepted = header.accepted.value.load(Ordering::Acquire);
+        match ShmSocket::connect(socket_definition(name, id, header.pipe_size)) {
+            // The socket is not created yet
+            Err(Errno::ENOENT) => {
+                if !header.is_listening() {
+                    return Err(Errno::ECONNREFUSED);
+                }
+                // Wakes up regularly to check the listener is still there
+                let mut wait = Duration::from_millis(100);
+                if let Some(deadline) = deadline {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        // Accepted already, the socket is about to be created
+                        if withdraw(header, id) {
+                            return Err(Errno::ETIMEDOUT);
+                        }
+                    } else {
+                        wait = wait.min(deadline - now);
+                    }
+                }
+                let _ = header.accepted.wait_for(accepted, wait);
+            }
+            result => return result,
+        }
+    }
+}
+
+// The listener skips the request if it did not accept it yet, false if it did.
+fn withdraw(header: &ListenerHeader, id: u64) -> bool {
+    lock_robust(&header.lock);
+    let withdrawn =
+        (header.head.load(Ordering::Relaxed)..header.tail.load(Ordering::Relaxed)).any(|i| {
+            header.queue[i % BACKLOG]
+                .compare_exchange(id, WITHDRAWN, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        });
+    unlock_robust(&header.lock);
+    withdrawn
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::errno::Errno;
+
+    use crate::common::{
+        listener::{connect, ShmListener},
+        ShmDefinition,
+    };
+
+    #[test_log::test]
+    fn listener_accepts_a_socket_per_client() {
+        let mut listener =
+            ShmListener::bind(ShmDefinition::new("service".to_string(), 1024)).unwrap();
+        let clients: Vec<_> = (0..3_u8)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut socket = connect("service", None).unwrap();
+                    socket.write_message(&[i]).unwrap();
+                    socket.read_message().unwrap()
+                })
+            })
+            .collect();
+
+        for _ in 0..3 {
+            let mut socket = listener.accept().unwrap();
+            let mut request = socket.read_message().unwrap();
+            request.push(42);
+            socket.write_message(&request).unwrap();
+        }
+
+        for (i, client) in clients.into_iter().enumerate() {
+            assert_eq!(vec![i as u8, 42], client.join().unwrap());
+        }
+    }
+
+    #[test_log::test]
+    fn connect_fails_when_the_listener_does_not_accept() {
+        let listener =
+            ShmListener::bind(ShmDefinition::new("busy_service".to_string(), 1024)).unwrap();
+        assert_eq!(
+            Some(Errno::ETIMEDOUT),
+            connect("busy_service", Some(Duration::from_millis(10))).err()
+        );
+
+        let client = thread::spawn(|| connect("busy_service", None).err());
+        // Once the request is queued
+        while unsafe { &*listener.header }.tail.load(Ordering::Relaxed) < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(listener);
+        assert_eq!(Some(Errno::ECONNREFUSED), client.join().unwrap());
+    }
+
+    #[test_log::test]
+    fn clients_timing_out_are_either_connected_or_skipped() {
+        let mut listener =
+            ShmListener::bind(ShmDefinition::new("racing_service".to_string(), 1024)).unwrap();
+        for round in 0..20 {
+            let racer = thread::spawn(move || {
+                match connect("racing_service", Some(Duration::from_micros(round * 20))) {
+                    Ok(mut socket) => {
+                        socket.write_message(b"racer").unwrap();
+                        socket.read_message().unwrap();
+                        true
+                    }
+                    Err(errno) => {
+                        assert_eq!(Errno::ETIMEDOUT, errno);
+                        false
+                    }
+                }
+            });
+            // Accepted after the racer whether it timed out or not
+            let sentinel = thread::spawn(move || {
+                let connected = racer.join().unwrap();
+                let mut socket = connect("racing_service", None).unwrap();
+                socket.write_message(b"sentinel").unwrap();
+                socket.read_message().unwrap();
+                connected
+            });
+
+            let mut messages = vec![];
+            while messages.last().map(Vec::as_slice) != Some(b"sentinel".as_slice()) {
+                let mut socket = listener.accept().unwrap();
+                messages.push(socket.read_message().unwrap());
+                socket.write_message(b"ok").unwrap();
+            }
+            assert_eq!(sentinel.join().unwrap(), messages.len() == 2);
+        }
+    }
+}
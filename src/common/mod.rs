@@ -1,13 +1,34 @@
 use std::hash::Hash;
 
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod clock;
+pub mod history;
+pub mod history_customer;
+pub mod history_owner;
+pub mod listener;
+pub mod notify;
+mod pipe;
+pub mod poller;
 mod pthread;
+pub mod queue;
 pub mod reader;
+pub mod rpc;
 pub mod shm;
 pub mod shm_syncer;
+pub mod socket;
+pub mod store;
 pub mod store_customer;
 pub mod store_owner;
+pub mod store_writer;
+pub mod stream;
 pub mod stream_consumer;
+pub mod stream_merge;
+pub mod stream_mux;
 pub mod stream_producer;
+pub mod topic;
+pub mod topic_consumer;
+pub mod topic_producer;
 pub mod writer;
 
 pub struct ShmDefinition {
@@ -28,8 +49,18 @@ pub struct TestRecord {
 
 impl Record<i32> for TestRecord {
     fn key(&self) -> i32 {
-        println!("{:?}", self.value);
-        self.value.0.clone()
+        self.value.0
+    }
+}
+
+unsafe impl PlainRecord for TestRecord {}
+
+impl Counter<i32> for TestRecord {
+    fn increment(&self, delta: i64) -> Option<Self> {
+        let value = self.value.1.checked_add(i32::try_from(delta).ok()?)?;
+        Some(TestRecord {
+            value: (self.value.0, value),
+        })
     }
 }
 
@@ -38,3 +69,15 @@ pub trait Key: Eq + Hash + Clone {}
 pub trait Record<K>: Copy {
     fn key(&self) -> K;
 }
+
+/// Records saved as their bytes in the snapshot files of a store.
+///
+/// # Safety
+/// The record has no padding and any bit pattern is a valid record.
+pub unsafe trait PlainRecord: Copy {}
+
+// Records holding a numeric value that the store can increment atomically.
+// None when the value cannot hold the result.
+pub trait Counter<K>: Record<K> {
+    fn increment(&self, delta: i64) -> Option<Self>;
+}
@@ -0,0 +1,257 @@
+use std::fs::read_dir;
+use std::mem::size_of;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use log::debug;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, mkdir, mkfifo, read, unlink, write};
+use nix::Result;
+
+use crate::common::shm::ShmMap;
+use crate::common::ShmDefinition;
+
+static NEXT_NOTIFICATION: AtomicU32 = AtomicU32::new(0);
+
+// Consumers register a fifo in this directory, the producers of the segment write a byte to each of them.
+fn notify_dir(name: &str) -> PathBuf {
+    PathBuf::from(format!("/dev/shm/{}_notify", name.trim_start_matches('/')))
+}
+
+// Lives in the header of the segment, producers only notify once a consumer registered.
+#[repr(C)]
+pub(crate) struct NotifyState {
+    consumers: AtomicU32,
+    // Bumped on every registration and drop, producers then list the fifos again.
+    generation: AtomicU32,
+}
+
+// Pollable file descriptor that becomes readable when the producer of the segment publishes.
+// It can be registered with epoll or mio along with sockets and timers.
+pub struct ShmNotification {
+    path: PathBuf,
+    fd: RawFd,
+    // Keeps the state mapped whatever the lifetime of the consumer
+    map: ShmMap,
+    state: *const NotifyState,
+}
+
+impl ShmNotification {
+    // The state is at the offset in the header of the segment.
+    pub(crate) fn register(name: &str, segment: &str, offset: usize) -> Result<Self> {
+        let map = ShmMap::open(ShmDefinition::new(
+            segment.to_string(),
+            offset + size_of::<NotifyState>(),
+        ))?;
+        let state = unsafe { map.start_ptr().add(offset) } as *const NotifyState;
+        let dir = notify_dir(name);
+        match mkdir(&dir, Mode::S_IRWXU) {
+            Ok(_) | Err(Errno::EEXIST) => (),
+            Err(e) => return Err(e),
+        }
+        let path = dir.join(format!(
+            "{}.{}",
+            std::process::id(),
+            NEXT_NOTIFICATION.fetch_add(1, Ordering::Relaxed)
+        ));
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        // Also opened for writing so that the fifo never reports a hang up
+        match open(
+            &path,
+            OFlag::O_RDWR | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(fd) => {
+                debug!("registered notification {:?}", path);
+                let state_ref = unsafe { &*state };
+                // Bumped first so that the producers seeing the consumer list the fifo
+                state_ref.generation.fetch_add(1, Ordering::Release);
+                state_ref.consumers.fetch_add(1, Ordering::AcqRel);
+                Ok(Self {
+                    path,
+                    fd,
+                    map,
+                    state,
+                })
+            }
+            Err(e) => {
+                let _ = unlink(&path);
+                Err(e)
+            }
+        }
+    }
+
+    // Makes the notification readable as if the producer published.
+    pub(crate) fn signal(&self) {
+        let _ = write(self.fd, &[1]);
+    }
+
+    // Must be called before consuming what was published, to be notified of what comes next.
+    pub fn clear(&self) {
+        let mut buffer = [0_u8; 64];
+        while let Ok(read) = read(self.fd, &mut buffer) {
+            if read < buffer.len() {
+                break;
+            }
+        }
+    }
+}
+
+impl AsRawFd for ShmNotification {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for ShmNotification {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl Drop for ShmNotification {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+        let _ = unlink(&self.path);
+        // Only succeeds for the last consumer
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+        let state = unsafe { &*self.state };
+        state.consumers.fetch_sub(1, Ordering::AcqRel);
+        state.generation.fetch_add(1, Ordering::Release);
+        debug!("unregistered notification of {}", self.map.name());
+    }
+}
+
+// Producer side, writes to the fifos registered for the segment.
+pub(crate) struct Notifier {
+    dir: PathBuf,
+    // Generation of the state when the directory was last listed
+    scanned: Option<u32>,
+    fifos: Vec<(PathBuf, RawFd)>,
+}
+
+impl Notifier {
+    pub fn new(name: &str) -> Self {
+        Self {
+            dir: notify_dir(name),
+            scanned: None,
+            fifos: Vec::new(),
+        }
+    }
+
+    // A single atomic load while no consumer is registered.
+    pub fn notify(&mut self, state: &NotifyState) {
+        if state.consumers.load(Ordering::Acquire) == 0 {
+            if self.scanned.take().is_some() {
+                self.close_all();
+            }
+            return;
+        }
+        let generation = Some(state.generation.load(Ordering::Acquire));
+        if generation != self.scanned {
+            self.scanned = generation;
+            self.scan();
+        }
+        self.fifos.retain(|(path, fd)| match write(*fd, &[1]) {
+            // A full fifo already notifies its consumer
+            Ok(_) | Err(Errno::EAGAIN) => true,
+            Err(_) => {
+                // The consumer is gone
+                let _ = close(*fd);
+                let _ = unlink(path);
+                false
+            }
+        });
+    }
+
+    fn scan(&mut self) {
+        self.close_all();
+        let entries = match read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            match open(
+                &path,
+                OFlag::O_WRONLY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            ) {
+                Ok(fd) => self.fifos.push((path, fd)),
+                // Nobody reads the fifo anymore
+                Err(Errno::ENXIO) => {
+                    let _ = unlink(&path);
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    fn close_all(&mut self) {
+        for (_, fd) in self.fifos.drain(..) {
+            let _ = close(fd);
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use std::os::unix::io::AsRawFd;
+
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    use super::{Notifier, NotifyState, ShmNotification};
+    use crate::common::shm::MutableShmMap;
+    use crate::common::ShmDefinition;
+
+    fn readable(notification: &ShmNotification) -> bool {
+        let mut fds = [PollFd::new(notification.as_raw_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, 0).unwrap() == 1
+    }
+
+    #[test_log::test]
+    fn notification_is_readable_once_notified() {
+        let map = MutableShmMap::create(ShmDefinition::new(
+            "notified".to_string(),
+            size_of::<NotifyState>(),
+        ))
+        .unwrap();
+        let state = unsafe { &*(map.start_ptr() as *const NotifyState) };
+        let mut notifier = Notifier::new("notified");
+        // Nothing to list without consumers
+        notifier.notify(state);
+        assert_eq!(None, notifier.scanned);
+
+        let notification = ShmNotification::register("notified", "notified", 0).unwrap();
+        assert!(!readable(&notification));
+
+        notifier.notify(state);
+        assert!(readable(&notification));
+        notification.clear();
+        assert!(!readable(&notification));
+
+        let later = ShmNotification::register("notified", "notified", 0).unwrap();
+        notifier.notify(state);
+        assert!(readable(&notification));
+        assert!(readable(&later));
+
+        drop(notification);
+        drop(later);
+        notifier.notify(state);
+        assert!(notifier.fifos.is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+use std::mem::size_of;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+
+use linux_futex::{Futex, Shared};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+use crate::common::notify::NotifyState;
+use crate::common::pthread::{lock_robust, unlock_robust, RobustLock};
+
+pub const MAX_READERS: usize = 8;
+
+// Read position of a reader registered by a process, a free cursor has no owner.
+#[repr(C)]
+pub(crate) struct PipeCursor {
+    pub owner: AtomicI32,
+    pub position: AtomicU64,
+}
+
+// Shared at the beginning of the segment of a byte pipe, followed by the data ring.
+#[repr(C)]
+pub(crate) struct PipeHeader {
+    // Size of the ring set by the writer, readers must open the segment with the same size.
+    pub capacity: AtomicU64,
+    // Number of bytes ever written, their offset in the ring is modulo its capacity.
+    pub write_position: AtomicU64,
+    // Oldest byte retained for the readers, the writer can overwrite the bytes before it.
+    pub tail: AtomicU64,
+    // Bumped on every write and on close to wake up the blocked reader.
+    pub written: Futex<Shared>,
+    // Bumped on every read to wake up the writer blocked on a full ring.
+    pub consumed: Futex<Shared>,
+    // Notifications of the readers and of the writer.
+    pub written_notify: NotifyState,
+    pub consumed_notify: NotifyState,
+    pub closed: AtomicU32,
+    // Taken to register the readers and move their cursors back.
+    pub lock: RobustLock,
+    // Bumped before and after the cursors move back, odd in between.
+    pub cursors_moved: AtomicU64,
+    pub readers: [PipeCursor; MAX_READERS],
+}
+
+impl PipeHeader {
+    // Moves the tail to the slowest reader, the bytes are retained while no reader is registered.
+    // Retried when a cursor moved back meanwhile, it may have been placed at the previous tail.
+    pub fn advance_tail(&self) -> u64 {
+        loop {
+            let moved = self.cursors_moved.load(Ordering::SeqCst);
+            if !moved.is_multiple_of(2) {
+                // Waits for the cursor to be placed
+                self.move_cursors(|| ());
+                continue;
+            }
+            let tail = self
+                .readers
+                .iter()
+                .filter(|cursor| cursor.owner.load(Ordering::Acquire) != 0)
+                .map(|cursor| cursor.position.load(Ordering::Acquire))
+                .min()
+                .unwrap_or_else(|| self.tail.load(Ordering::Relaxed));
+            self.tail.store(tail, Ordering::SeqCst);
+            if self.cursors_moved.load(Ordering::SeqCst) == moved {
+                return tail;
+            }
+        }
+    }
+
+    // Runs the function registering a cursor at the tail or moving one back.
+    pub fn move_cursors<T>(&self, f: impl FnOnce() -> T) -> T {
+        let recovered = lock_robust(&self.lock);
+        // A dead reader may have left the count odd
+        if !recovered || self.cursors_moved.load(Ordering::Relaxed).is_multiple_of(2) {
+            self.cursors_moved.fetch_add(1, Ordering::SeqCst);
+        }
+        let result = f();
+        self.cursors_moved.fetch_add(1, Ordering::SeqCst);
+        unlock_robust(&self.lock);
+        result
+    }
+
+    // Frees the cursors of the processes that died without closing their reader.
+    pub fn release_dead_readers(&self) {
+        for cursor in &self.readers {
+            let owner = cursor.owner.load(Ordering::Acquire);
+            if owner != 0 && kill(Pid::from_raw(owner), None) == Err(Errno::ESRCH) {
+                let _ =
+                    cursor
+                        .owner
+                        .compare_exchange(owner, 0, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Name the notifications of the consumed bytes are registered with.
+pub(crate) fn consumed_name(name: &str) -> String {
+    format!("{}_consumed", name)
+}
+
+pub(crate) fn ring_capacity(size: usize) -> usize {
+    size.saturating_sub(size_of::<PipeHeader>())
+}
+
+pub(crate) fn ring_ptr(start_ptr: *mut u8) -> *mut u8 {
+    unsafe { start_ptr.add(size_of::<PipeHeader>()) }
+}
+
+// Copies the bytes at the position, wrapping at the end of the ring.
+pub(crate) unsafe fn copy_in(ring: *mut u8, capacity: usize, position: u64, bytes: &[u8]) {
+    let offset = (position % capacity as u64) as usize;
+    let first = bytes.len().min(capacity - offset);
+    ring.add(offset).copy_from(bytes.as_ptr(), first);
+    ring.copy_from(bytes[first..].as_ptr(), bytes.len() - first);
+}
+
+pub(crate) unsafe fn copy_out(ring: *const u8, capacity: usize, position: u64, out: &mut [u8]) {
+    let offset = (position % capacity as u64) as usize;
+    let first = out.len().min(capacity - offset);
+    ring.add(offset).copy_to(out.as_mut_ptr(), first);
+    ring.copy_to(out[first..].as_mut_ptr(), out.len() - first);
+}
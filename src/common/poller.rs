@@ -0,0 +1,117 @@
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::Result;
+
+use crate::common::notify::ShmNotification;
+
+// Waits on the notifications of many consumers at once.
+pub struct ShmPoller {
+    notifications: Vec<ShmNotification>,
+}
+
+impl ShmPoller {
+    pub fn new() -> Self {
+        Self {
+            notifications: Vec::new(),
+        }
+    }
+
+    // Returns the token of the source in the results of wait.
+    // The source is ready at first, to consume what was published before it was added.
+    pub fn add(&mut self, notification: ShmNotification) -> usize {
+        notification.signal();
+        self.notifications.push(notification);
+        self.notifications.len() - 1
+    }
+
+    // Blocks until some sources are ready and returns their tokens, none once the timeout elapsed.
+    // The ready sources must then be consumed without blocking, they may have nothing left.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<usize>> {
+        let mut fds: Vec<PollFd> = self
+            .notifications
+            .iter()
+            .map(|notification| PollFd::new(notification.as_raw_fd(), PollFlags::POLLIN))
+            .collect();
+        // Rounded up, a timeout under a millisecond would not wait at all
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+        });
+        loop {
+            match poll(&mut fds, timeout) {
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+                Ok(_) => break,
+            }
+        }
+        Ok(fds
+            .iter()
+            .zip(self.notifications.iter())
+            .enumerate()
+            .filter(|(_, (fd, _))| {
+                fd.revents()
+                    .is_some_and(|revents| revents.contains(PollFlags::POLLIN))
+            })
+            .map(|(token, (_, notification))| {
+                notification.clear();
+                token
+            })
+            .collect())
+    }
+}
+
+impl Default for ShmPoller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::common::{poller::ShmPoller, stream_consumer, stream_producer, ShmDefinition};
+
+    #[test_log::test]
+    fn poller_returns_the_ready_streams() {
+        let mut first: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open(ShmDefinition::new("polled_1".to_string(), 1024))
+                .unwrap();
+        let mut second: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open(ShmDefinition::new("polled_2".to_string(), 1024))
+                .unwrap();
+        first.insert(1).unwrap();
+
+        let mut consumers: Vec<stream_consumer::ShmStream<u64>> = ["polled_1", "polled_2"]
+            .iter()
+            .map(|name| {
+                stream_consumer::ShmStream::open(ShmDefinition::new(name.to_string(), 1024))
+                    .unwrap()
+            })
+            .collect();
+        let mut poller = ShmPoller::new();
+        for consumer in &consumers {
+            poller.add(consumer.notification().unwrap());
+        }
+
+        assert_eq!(vec![0, 1], poller.wait(None).unwrap());
+        assert_eq!(Some(1), consumers[0].try_next());
+        assert_eq!(None, consumers[1].try_next());
+        assert!(poller
+            .wait(Some(Duration::from_millis(10)))
+            .unwrap()
+            .is_empty());
+        let start = Instant::now();
+        assert!(poller
+            .wait(Some(Duration::from_micros(100)))
+            .unwrap()
+            .is_empty());
+        assert!(start.elapsed() >= Duration::from_micros(100));
+
+        second.insert(2).unwrap();
+        assert_eq!(vec![1], poller.wait(None).unwrap());
+        assert_eq!(Some(2), consumers[1].try_next());
+    }
+}
@@ -1,30 +1,32 @@
-use std::sync::{atomic::Ordering, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
 
 use linux_futex::{Futex, Shared, WaitError};
-use log::debug;
+use log::{debug, warn};
 
 use super::shm::{MutableShmMap, ShmMap};
 
+#[allow(dead_code)]
 pub struct ShmMutex<T> {
     _shm: T,
     ptr: *mut Mutex<u8>,
 }
 
+#[allow(dead_code)]
 impl ShmMutex<MutableShmMap> {
     pub fn init_in_shm(shm: MutableShmMap) -> Self {
         unsafe {
             let ptr = shm.start_ptr() as *mut Mutex<u8>;
             debug!("created mutex at {:?}", *shm.start_ptr());
-            ShmMutex::<MutableShmMap> {
-                _shm: shm,
-                ptr: ptr,
-            }
+            ShmMutex::<MutableShmMap> { _shm: shm, ptr }
         }
     }
 }
 
+#[allow(dead_code)]
 impl<T> ShmMutex<T> {
-    pub fn lock(&mut self) -> MutexGuard<u8> {
+    pub fn lock(&mut self) -> MutexGuard<'_, u8> {
         unsafe {
             debug!("locking mutex at {:?}", *self.ptr);
             let guard = (*self.ptr).lock().unwrap();
@@ -34,14 +36,12 @@ impl<T> ShmMutex<T> {
     }
 }
 
+#[allow(dead_code)]
 impl ShmMutex<ShmMap> {
     pub fn from_raw_pointer(shm: ShmMap) -> Self {
         let ptr = shm.start_ptr() as *mut Mutex<u8>;
         unsafe { debug!("initialized mutex at {:?}", *ptr) };
-        ShmMutex {
-            _shm: shm,
-            ptr: ptr,
-        }
+        ShmMutex { _shm: shm, ptr }
     }
 }
 
@@ -56,10 +56,7 @@ impl ShmCondition<MutableShmMap> {
         unsafe {
             (*ptr).value.store(1, Ordering::Relaxed);
             debug!("created cond at {:?}", *ptr);
-            ShmCondition {
-                _shm: shm,
-                ptr: ptr,
-            }
+            ShmCondition { _shm: shm, ptr }
         }
     }
 
@@ -73,7 +70,7 @@ impl ShmCondition<MutableShmMap> {
 }
 
 impl<T> ShmCondition<T> {
-    pub fn wait(&mut self, mutex: &mut Mutex<u8>) {
+    pub fn wait(&mut self, mutex: &Mutex<u8>) {
         unsafe {
             debug!("Waiting on condition at {:?}", *self.ptr);
             let expected_value = {
@@ -99,13 +96,109 @@ impl ShmCondition<ShmMap> {
     pub fn from_raw_pointer(shm: ShmMap) -> Self {
         let ptr = shm.start_ptr() as *mut Futex<Shared>;
         unsafe { debug!("initialized cond at {:?}", *ptr) };
-        ShmCondition {
-            _shm: shm,
-            ptr: ptr,
+        ShmCondition { _shm: shm, ptr }
+    }
+}
+
+// Process shared lock owned by a process identified by its pid and start time, so that it can
+// be taken over when the owner died, even if its pid was reused since.
+#[repr(C)]
+pub(crate) struct RobustLock {
+    pub owner: Futex<Shared>,
+    // Start time of the owner, 0 while the lock is being taken.
+    pub started: AtomicU64,
+}
+
+impl RobustLock {
+    pub fn init(&self) {
+        self.started.store(0, Ordering::Relaxed);
+        self.owner.value.store(0, Ordering::Release);
+    }
+}
+
+// Start time of the process in clock ticks after boot, None if it does not exist.
+fn start_time(pid: i32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name can hold spaces and parentheses, the start time is the 22nd field
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()
+}
+
+static OWN_PID: AtomicI32 = AtomicI32::new(0);
+static OWN_START_TIME: AtomicU64 = AtomicU64::new(0);
+
+// Cached by pid, a forked child has its own start time.
+fn own_start_time(pid: i32) -> u64 {
+    if OWN_PID.load(Ordering::Acquire) != pid {
+        OWN_START_TIME.store(start_time(pid).unwrap_or(0), Ordering::Relaxed);
+        OWN_PID.store(pid, Ordering::Release);
+    }
+    OWN_START_TIME.load(Ordering::Relaxed)
+}
+
+fn is_alive(pid: i32, started: u64) -> bool {
+    match start_time(pid) {
+        Some(start_time) => started == 0 || started == start_time,
+        None => false,
+    }
+}
+
+// Returns true if the lock was taken over from a dead owner.
+pub(crate) fn lock_robust(lock: &RobustLock) -> bool {
+    let pid = std::process::id() as i32;
+    loop {
+        match lock
+            .owner
+            .value
+            .compare_exchange(0, pid, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                lock.started.store(own_start_time(pid), Ordering::Release);
+                return false;
+            }
+            Err(owner) => {
+                let started = lock.started.load(Ordering::Acquire);
+                // The start time is cleared first, the new owner is never seen with the old one
+                if !is_alive(owner, started)
+                    && lock
+                        .started
+                        .compare_exchange(started, 0, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_ok()
+                    && lock
+                        .owner
+                        .value
+                        .compare_exchange(owner, pid, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                {
+                    lock.started.store(own_start_time(pid), Ordering::Release);
+                    warn!("took over lock from dead process {}", owner);
+                    return true;
+                }
+                // Wake up regularly to check the owner is still alive
+                let _ = lock.owner.wait_for(owner, Duration::from_millis(10));
+            }
         }
     }
 }
 
+// Whether the lock is held by a process that died.
+pub(crate) fn is_abandoned(lock: &RobustLock) -> bool {
+    let owner = lock.owner.value.load(Ordering::Acquire);
+    let started = lock.started.load(Ordering::Acquire);
+    // The owner may have changed in between
+    owner != 0 && !is_alive(owner, started) && lock.owner.value.load(Ordering::Acquire) == owner
+}
+
+pub(crate) fn unlock_robust(lock: &RobustLock) {
+    lock.started.store(0, Ordering::Release);
+    lock.owner.value.store(0, Ordering::Release);
+    lock.owner.wake(1);
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -134,13 +227,13 @@ mod tests {
         });
         let client = std::thread::spawn(|| {
             std::thread::sleep(Duration::from_secs(2));
-            let mut mutex = Mutex::new(0);
+            let mutex = Mutex::new(0);
             let condvar_definition =
                 ShmDefinition::new("condition".to_string(), std::mem::size_of::<Condvar>());
             let condvar_shm = ShmMap::open(condvar_definition).unwrap();
             let mut condition = ShmCondition::from_raw_pointer(condvar_shm);
 
-            condition.wait(&mut mutex);
+            condition.wait(&mutex);
             true
         });
         assert!(client.join().unwrap());
@@ -0,0 +1,160 @@
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use linux_futex::{Futex, Shared};
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::pthread::{lock_robust, unlock_robust, RobustLock};
+use crate::common::shm::{MutableShmMap, ShmMap};
+use crate::common::ShmDefinition;
+
+// Shared at the beginning of the segment of a queue, followed by its entries.
+#[repr(C)]
+struct QueueHeader {
+    capacity: usize,
+    // Serializes the producers
+    lock: RobustLock,
+    // Bumped on every push and pop to wake up the consumer and the producers.
+    pushed: Futex<Shared>,
+    popped: Futex<Shared>,
+    head: AtomicU64,
+    tail: AtomicU64,
+    // Set once the consumer stopped popping.
+    closed: AtomicU32,
+}
+
+fn entries_offset<E>() -> usize {
+    size_of::<QueueHeader>().next_multiple_of(align_of::<E>())
+}
+
+// Bounded queue of entries pushed by any number of processes and popped by a single one.
+pub struct ShmQueue<E: Copy, T> {
+    _map: T,
+    header: *const QueueHeader,
+    entries: *mut E,
+    _entries: PhantomData<E>,
+}
+
+impl<E: Copy> ShmQueue<E, MutableShmMap> {
+    pub fn create(definition: ShmDefinition) -> Result<Self> {
+        let capacity = definition.size.saturating_sub(entries_offset::<E>()) / size_of::<E>();
+        if capacity == 0 {
+            return Err(Errno::EINVAL);
+        }
+        MutableShmMap::create(definition).map(|m| {
+            let header = m.start_ptr() as *mut QueueHeader;
+            unsafe {
+                (*header).capacity = capacity;
+                (*header).lock.init();
+                (*header).pushed.value.store(0, Ordering::Relaxed);
+                (*header).popped.value.store(0, Ordering::Relaxed);
+                (*header).head.store(0, Ordering::Relaxed);
+                (*header).closed.store(0, Ordering::Relaxed);
+                (*header).tail.store(0, Ordering::Release);
+            }
+            Self::from_map(m.start_ptr(), m)
+        })
+    }
+}
+
+impl<E: Copy> ShmQueue<E, ShmMap> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        ShmMap::open(definition).map(|m| Self::from_map(m.start_ptr() as *mut u8, m))
+    }
+}
+
+impl<E: Copy, T> ShmQueue<E, T> {
+    fn from_map(start_ptr: *mut u8, map: T) -> Self {
+        Self {
+            _map: map,
+            header: start_ptr as *const QueueHeader,
+            entries: unsafe { start_ptr.add(entries_offset::<E>()) as *mut E },
+            _entries: PhantomData,
+        }
+    }
+
+    // Waits for room in the queue, up to the timeout if any.
+    pub fn push(&self, entry: E, timeout: Option<Duration>) -> Result<()> {
+        let header = self.header();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let popped = header.popped.value.load(Ordering::Acquire);
+            lock_robust(&header.lock);
+            let tail = header.tail.load(Ordering::Relaxed);
+            let full = tail - header.head.load(Ordering::Acquire) == header.capacity as u64;
+            if !full {
+                unsafe {
+                    self.entries
+                        .add((tail % header.capacity as u64) as usize)
+                        .write_volatile(entry)
+                };
+                header.tail.store(tail + 1, Ordering::Release);
+            }
+            unlock_robust(&header.lock);
+            if !full {
+                notify(&header.pushed);
+                return Ok(());
+            }
+            wait(&header.popped, popped, deadline)?;
+        }
+    }
+
+    // Waits for an entry, up to the timeout if any. Only one process may pop.
+    pub fn pop(&self, timeout: Option<Duration>) -> Result<E> {
+        let header = self.header();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let pushed = header.pushed.value.load(Ordering::Acquire);
+            let head = header.head.load(Ordering::Relaxed);
+            if head < header.tail.load(Ordering::Acquire) {
+                let entry = unsafe {
+                    self.entries
+                        .add((head % header.capacity as u64) as usize)
+                        .read_volatile()
+                };
+                header.head.store(head + 1, Ordering::Release);
+                notify(&header.popped);
+                return Ok(entry);
+            }
+            wait(&header.pushed, pushed, deadline)?;
+        }
+    }
+
+    // Tells the producers that nothing is popped anymore.
+    pub fn close(&self) {
+        self.header().closed.store(1, Ordering::Release);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.header().closed.load(Ordering::Acquire) == 1
+    }
+
+    fn header(&self) -> &QueueHeader {
+        unsafe { &*self.header }
+    }
+}
+
+fn notify(futex: &Futex<Shared>) {
+    futex.value.fetch_add(1, Ordering::Release);
+    futex.wake(i32::MAX);
+}
+
+// Fails with ETIMEDOUT once the deadline passed.
+fn wait(futex: &Futex<Shared>, value: i32, deadline: Option<Instant>) -> Result<()> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Errno::ETIMEDOUT);
+            }
+            let _ = futex.wait_for(value, deadline - now);
+        }
+        None => {
+            let _ = futex.wait(value);
+        }
+    }
+    Ok(())
+}
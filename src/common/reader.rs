@@ -1,47 +1,433 @@
-use std::io::Read;
+use std::io::{BufRead, ErrorKind, Read, Seek, SeekFrom};
+use std::mem::{offset_of, size_of};
+use std::slice;
+use std::sync::atomic::Ordering;
 
+use nix::errno::Errno;
 use nix::Result;
 
+use crate::common::notify::{Notifier, ShmNotification};
+use crate::common::pipe::{
+    consumed_name, copy_out, ring_capacity, ring_ptr, PipeCursor, PipeHeader,
+};
 use crate::common::shm::ShmMap;
 use crate::common::ShmDefinition;
 
+// Each reader gets all the bytes from its own cursor in the pipe.
 pub struct ShmReader {
     map: ShmMap,
-    written_bytes_ptr: *const u8,
-    last_read_ptr: *const u8,
-    read: usize,
+    header: *const PipeHeader,
+    cursor: *const PipeCursor,
+    ring: *const u8,
+    capacity: usize,
+    nonblocking: bool,
+    message: Vec<u8>,
+    consumed: Notifier,
 }
 
 impl ShmReader {
+    // Registers a cursor at the oldest byte retained by the writer.
+    // Fails with EINVAL unless the size is the one the writer opened the segment with.
     pub fn open(definition: ShmDefinition) -> Result<Self> {
-        ShmMap::open(definition).map(|m| {
-            // We keep the number of written bytes of the beginning
-            let written_bytes_ptr = m.start_ptr();
-            let last_read_ptr = unsafe { written_bytes_ptr.add(1) };
-            Self {
+        let size = definition.size;
+        if ring_capacity(size) == 0 {
+            return Err(Errno::EINVAL);
+        }
+        // Checked on the header alone, a larger mapping would grow the segment of the writer
+        let header_definition =
+            ShmDefinition::new(definition.name.clone(), size_of::<PipeHeader>());
+        let capacity = ShmMap::open(header_definition).map(|m| {
+            unsafe { &*(m.start_ptr() as *const PipeHeader) }
+                .capacity
+                .load(Ordering::Acquire)
+        })?;
+        if capacity != ring_capacity(size) as u64 {
+            return Err(Errno::EINVAL);
+        }
+        let consumed = Notifier::new(&consumed_name(&definition.name));
+        ShmMap::open(definition).and_then(|m| {
+            // We keep the write position and the reader cursors at the beginning
+            let header = unsafe { &*(m.start_ptr() as *const PipeHeader) };
+            let pid = std::process::id() as i32;
+            let cursor = header.move_cursors(|| {
+                let cursor = header.readers.iter().find(|cursor| {
+                    cursor
+                        .owner
+                        .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_ok()
+                });
+                if let Some(cursor) = cursor {
+                    let tail = header.tail.load(Ordering::SeqCst);
+                    cursor.position.store(tail, Ordering::Release);
+                }
+                cursor
+            });
+            cursor.ok_or(Errno::EUSERS).map(|cursor| Self {
+                ring: ring_ptr(m.start_ptr() as *mut u8),
+                header,
+                cursor,
                 map: m,
-                written_bytes_ptr: written_bytes_ptr,
-                last_read_ptr: last_read_ptr,
-                read: 0,
-            }
+                capacity: ring_capacity(size),
+                nonblocking: false,
+                message: Vec::new(),
+                consumed,
+            })
         })
     }
+
+    // Reads then fail with WouldBlock instead of waiting for the writer.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    // Reads without waiting whatever the mode of the reader.
+    #[cfg(feature = "async")]
+    pub(crate) fn read_nonblocking(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let nonblocking = std::mem::replace(&mut self.nonblocking, true);
+        let result = self.read(out);
+        self.nonblocking = nonblocking;
+        result
+    }
+
+    // Readable once the writer wrote or closed, see ShmNotification::clear.
+    pub fn notification(&self) -> Result<ShmNotification> {
+        let name = self.map.name();
+        ShmNotification::register(name, name, offset_of!(PipeHeader, written_notify))
+    }
+
+    // Next message of write_message, the end of file is an UnexpectedEof error.
+    // Messages must not be mixed with the bytes of plain writes.
+    pub fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        self.read_message_ref().map(|message| message.to_vec())
+    }
+
+    // Borrows the message from a buffer reused by the subsequent reads.
+    pub fn read_message_ref(&mut self) -> std::io::Result<&[u8]> {
+        let mut length = [0; size_of::<u32>()];
+        let readable = self.wait_readable(length.len())?;
+        if readable < length.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let read_position = self.read_position();
+        unsafe { copy_out(self.ring, self.capacity, read_position, &mut length) };
+        // The message was published along with its length, a longer one is not a message
+        let message_length = u32::from_le_bytes(length) as usize;
+        if message_length > self.capacity - length.len() || message_length > readable - length.len()
+        {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        self.message.resize(message_length, 0);
+        unsafe {
+            copy_out(
+                self.ring,
+                self.capacity,
+                read_position + length.len() as u64,
+                &mut self.message,
+            )
+        };
+        self.advance(read_position + (length.len() + self.message.len()) as u64);
+        Ok(&self.message)
+    }
+
+    // Blocks until the size can be read, returns less only once the writer is closed.
+    fn wait_readable(&self, size: usize) -> std::io::Result<usize> {
+        let header = unsafe { &*self.header };
+        let read_position = self.read_position();
+        loop {
+            let written = header.written.value.load(Ordering::Acquire);
+            // The writer closes after its last write
+            let closed = header.closed.load(Ordering::Acquire) == 1;
+            let write_position = header.write_position.load(Ordering::Acquire);
+            let readable_size = (write_position - read_position) as usize;
+            if readable_size >= size || closed {
+                return Ok(readable_size);
+            }
+            if self.nonblocking {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            // Woken up or interrupted, the positions are checked again
+            let _ = header.written.wait(written);
+        }
+    }
+
+    fn read_position(&self) -> u64 {
+        unsafe { &*self.cursor }.position.load(Ordering::Relaxed)
+    }
+
+    // The writer can then reuse the bytes once the other readers read them as well
+    fn advance(&mut self, read_position: u64) {
+        let header = unsafe { &*self.header };
+        unsafe { &*self.cursor }
+            .position
+            .store(read_position, Ordering::Release);
+        header.consumed.value.fetch_add(1, Ordering::Release);
+        header.consumed.wake(i32::MAX);
+        self.consumed.notify(&header.consumed_notify);
+    }
+}
+
+impl Drop for ShmReader {
+    fn drop(&mut self) {
+        unsafe { &*self.cursor }.owner.store(0, Ordering::Release);
+        let header = unsafe { &*self.header };
+        header.consumed.value.fetch_add(1, Ordering::Release);
+        header.consumed.wake(i32::MAX);
+        self.consumed.notify(&header.consumed_notify);
+    }
 }
 
 impl Read for ShmReader {
+    // Blocks until bytes are written, returns 0 only once the writer is closed.
     fn read(&mut self, out: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
-        let readable_size = out
-            .len()
-            .min(unsafe { *self.written_bytes_ptr as usize } - self.read);
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let readable_size = self.wait_readable(1)?.min(out.len());
         if readable_size > 0 {
+            let read_position = self.read_position();
             unsafe {
-                self.last_read_ptr.copy_to(out.as_mut_ptr(), readable_size);
-                self.last_read_ptr = self.last_read_ptr.add(readable_size);
+                copy_out(
+                    self.ring,
+                    self.capacity,
+                    read_position,
+                    &mut out[..readable_size],
+                )
+            };
+            self.advance(read_position + readable_size as u64);
+        }
+        Ok(readable_size)
+    }
+}
+
+impl BufRead for ShmReader {
+    // Borrows the bytes up to the end of the ring, the cursor retains them until consumed.
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let readable_size = self.wait_readable(1)?;
+        let offset = (self.read_position() % self.capacity as u64) as usize;
+        let size = readable_size.min(self.capacity - offset);
+        Ok(unsafe { slice::from_raw_parts(self.ring.add(offset), size) })
+    }
+
+    // Consuming more than fill_buf returned consumes what it returned.
+    fn consume(&mut self, amt: usize) {
+        let offset = (self.read_position() % self.capacity as u64) as usize;
+        let write_position = unsafe { &*self.header }
+            .write_position
+            .load(Ordering::Acquire);
+        let readable_size = (write_position - self.read_position()) as usize;
+        let amt = amt.min(readable_size).min(self.capacity - offset);
+        self.advance(self.read_position() + amt as u64);
+    }
+}
+
+impl Seek for ShmReader {
+    // Positions are offsets in the whole byte stream, only the retained ones can be reached.
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let header = unsafe { &*self.header };
+        let write_position = header.write_position.load(Ordering::Acquire);
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => write_position.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.read_position().checked_add_signed(offset),
+        };
+        // The writer can not advance the tail while the cursor moves back
+        header.move_cursors(|| match target {
+            Some(target)
+                if target >= header.tail.load(Ordering::SeqCst) && target <= write_position =>
+            {
+                self.advance(target);
+                Ok(target)
             }
-            self.read = self.read + readable_size;
-            Ok(readable_size)
-        } else {
-            Ok(0)
+            _ => Err(ErrorKind::InvalidInput.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::mem::size_of;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd, PollFlags};
+
+    use crate::common::{pipe::PipeHeader, reader::ShmReader, writer::ShmWriter, ShmDefinition};
+
+    fn pipe(name: &str, capacity: usize) -> ShmDefinition {
+        ShmDefinition::new(name.to_string(), size_of::<PipeHeader>() + capacity)
+    }
+
+    #[test_log::test]
+    fn pipe_carries_more_bytes_than_its_segment() {
+        let mut writer = ShmWriter::open(pipe("pipe", 16)).unwrap();
+        let mut reader = ShmReader::open(pipe("pipe", 16)).unwrap();
+        let mut buffer = [0_u8; 16];
+
+        for i in 0..100_u8 {
+            let message = [i; 10];
+            assert_eq!(10, writer.write(&message).unwrap());
+            assert_eq!(10, reader.read(&mut buffer).unwrap());
+            assert_eq!(message, buffer[..10]);
+        }
+
+        assert_eq!(16, writer.write(&[1; 20]).unwrap());
+        writer.set_nonblocking(true);
+        assert_eq!(
+            ErrorKind::WouldBlock,
+            writer.write(&[1; 20]).unwrap_err().kind()
+        );
+        assert_eq!(16, reader.read(&mut buffer).unwrap());
+        reader.set_nonblocking(true);
+        assert_eq!(
+            ErrorKind::WouldBlock,
+            reader.read(&mut buffer).unwrap_err().kind()
+        );
+    }
+
+    #[test_log::test]
+    fn readers_open_the_size_of_the_writer() {
+        let _writer = ShmWriter::open(pipe("sized_pipe", 32)).unwrap();
+        for capacity in [16, 64] {
+            assert_eq!(
+                Some(Errno::EINVAL),
+                ShmReader::open(pipe("sized_pipe", capacity)).err()
+            );
+        }
+        assert!(ShmReader::open(pipe("sized_pipe", 32)).is_ok());
+    }
+
+    #[test_log::test]
+    fn reader_blocks_until_the_writer_writes_or_closes() {
+        let mut writer = ShmWriter::open(pipe("blocking_pipe", 32)).unwrap();
+        let client = thread::spawn(|| {
+            let mut reader = ShmReader::open(pipe("blocking_pipe", 32)).unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        for i in 0..10_u8 {
+            thread::sleep(Duration::from_millis(10));
+            writer.write_all(&[i; 4]).unwrap();
+        }
+        drop(writer);
+
+        let received = client.join().unwrap();
+        assert_eq!(40, received.len());
+        assert_eq!([9; 4], received[36..]);
+    }
+
+    #[test_log::test]
+    fn writer_blocks_until_the_reader_frees_space() {
+        let writer = ShmWriter::open(pipe("full_pipe", 32)).unwrap();
+        let client = thread::spawn(|| {
+            let mut reader = ShmReader::open(pipe("full_pipe", 32)).unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut writer = BufWriter::new(writer);
+        for i in 0..1000_u32 {
+            writer.write_all(&i.to_le_bytes()).unwrap();
         }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let received = client.join().unwrap();
+        assert_eq!(4000, received.len());
+        assert_eq!(999_u32.to_le_bytes(), received[3996..]);
+    }
+
+    #[test_log::test]
+    fn messages_keep_their_boundaries() {
+        let mut writer = ShmWriter::open(pipe("message_pipe", 32)).unwrap();
+        let client = thread::spawn(|| {
+            let mut reader = ShmReader::open(pipe("message_pipe", 32)).unwrap();
+            let mut messages = Vec::new();
+            while let Ok(message) = reader.read_message() {
+                messages.push(message);
+            }
+            messages
+        });
+
+        for i in 0..100_usize {
+            writer.write_message(&vec![i as u8; i % 20]).unwrap();
+        }
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            writer.write_message(&[0; 64]).unwrap_err().kind()
+        );
+        drop(writer);
+
+        let messages = client.join().unwrap();
+        assert_eq!(100, messages.len());
+        assert_eq!(vec![99; 19], messages[99]);
+    }
+
+    #[test_log::test]
+    fn message_longer_than_the_ring_is_invalid() {
+        let mut writer = ShmWriter::open(pipe("bad_message_pipe", 32)).unwrap();
+        let mut reader = ShmReader::open(pipe("bad_message_pipe", 32)).unwrap();
+
+        writer.write_all(&29_u32.to_le_bytes()).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            reader.read_message_ref().unwrap_err().kind()
+        );
+    }
+
+    #[test_log::test]
+    fn every_reader_gets_all_the_bytes() {
+        let mut writer = ShmWriter::open(pipe("shared_pipe", 16)).unwrap();
+        let mut first = ShmReader::open(pipe("shared_pipe", 16)).unwrap();
+        let mut second = ShmReader::open(pipe("shared_pipe", 16)).unwrap();
+        writer.write_all(b"one\ntwo\n").unwrap();
+
+        let mut line = String::new();
+        first.read_line(&mut line).unwrap();
+        assert_eq!("one\n", line);
+        // The second reader still retains the bytes
+        assert_eq!(8, writer.available());
+        first.seek(SeekFrom::Start(0)).unwrap();
+        let mut bytes = [0; 8];
+        first.read_exact(&mut bytes).unwrap();
+        second.read_exact(&mut bytes).unwrap();
+        assert_eq!(b"one\ntwo\n", &bytes);
+        assert_eq!(16, writer.available());
+
+        writer.write_all(&[1; 16]).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            first.seek(SeekFrom::Start(0)).unwrap_err().kind()
+        );
+        assert_eq!(8, first.stream_position().unwrap());
+
+        // Consuming more than fill_buf returned stops at the end of the ring
+        assert_eq!(8, first.fill_buf().unwrap().len());
+        first.consume(32);
+        assert_eq!(16, first.stream_position().unwrap());
+    }
+
+    #[test_log::test]
+    fn notification_fd_polls_readable_on_write() {
+        let mut writer = ShmWriter::open(pipe("polled_pipe", 64)).unwrap();
+        let mut reader = ShmReader::open(pipe("polled_pipe", 64)).unwrap();
+        reader.set_nonblocking(true);
+        let notification = reader.notification().unwrap();
+
+        let mut fds = [PollFd::new(notification.as_raw_fd(), PollFlags::POLLIN)];
+        assert_eq!(0, poll(&mut fds, 0).unwrap());
+        writer.write_all(b"ping").unwrap();
+        assert_eq!(1, poll(&mut fds, 1000).unwrap());
+
+        notification.clear();
+        let mut bytes = [0; 4];
+        reader.read_exact(&mut bytes).unwrap();
+        assert_eq!(b"ping", &bytes);
+        assert_eq!(0, poll(&mut fds, 0).unwrap());
     }
 }
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use nix::Result;
+
+use crate::common::queue::ShmQueue;
+use crate::common::shm::{MutableShmMap, ShmMap};
+use crate::common::ShmDefinition;
+
+#[derive(Clone, Copy)]
+struct RpcRequest<Q> {
+    client: u64,
+    correlation: u64,
+    request: Q,
+}
+
+#[derive(Clone, Copy)]
+struct RpcReply<P> {
+    correlation: u64,
+    reply: P,
+}
+
+// Request received by the server, to be answered with reply.
+pub struct RpcCall<Q> {
+    pub request: Q,
+    client: u64,
+    correlation: u64,
+}
+
+fn reply_definition(name: &str, client: u64, size: usize) -> ShmDefinition {
+    ShmDefinition::new(format!("{}.{}", name, client), size)
+}
+
+// The ids of the clients start with the pid of their process.
+fn is_process_alive(client: u64) -> bool {
+    kill(Pid::from_raw((client >> 32) as i32), None) != Err(Errno::ESRCH)
+}
+
+// Pops the requests of all the clients and pushes the replies to the channel of each one.
+pub struct RpcServer<Q: Copy, P: Copy> {
+    name: String,
+    size: usize,
+    requests: ShmQueue<RpcRequest<Q>, MutableShmMap>,
+    replies: HashMap<u64, ShmQueue<RpcReply<P>, ShmMap>>,
+}
+
+impl<Q: Copy, P: Copy> RpcServer<Q, P> {
+    // The size of the definition is the size of the request queue and of every reply channel.
+    pub fn bind(definition: ShmDefinition) -> Result<Self> {
+        let name = definition.name.clone();
+        let size = definition.size;
+        ShmQueue::create(definition).map(|requests| Self {
+            name,
+            size,
+            requests,
+            replies: HashMap::new(),
+        })
+    }
+
+    pub fn receive(&mut self, timeout: Option<Duration>) -> Result<RpcCall<Q>> {
+        self.requests.pop(timeout).map(|request| RpcCall {
+            request: request.request,
+            client: request.client,
+            correlation: request.correlation,
+        })
+    }
+
+    // Fails with EAGAIN rather than waiting for a client that does not read its replies,
+    // and with EPIPE once the client disconnected.
+    pub fn reply(&mut self, call: &RpcCall<Q>, reply: P) -> Result<()> {
+        if !self.replies.contains_key(&call.client) {
+            // The channels of the clients gone since are dropped along the way
+            self.replies
+                .retain(|client, channel| !channel.is_closed() && is_process_alive(*client));
+            let channel = ShmQueue::open(reply_definition(&self.name, call.client, self.size))?;
+            self.replies.insert(call.client, channel);
+        }
+        if self.replies[&call.client].is_closed() {
+            self.replies.remove(&call.client);
+            return Err(Errno::EPIPE);
+        }
+        let reply = RpcReply {
+            correlation: call.correlation,
+            reply,
+        };
+        match self.replies[&call.client].push(reply, Some(Duration::ZERO)) {
+            Err(Errno::ETIMEDOUT) => Err(Errno::EAGAIN),
+            result => result,
+        }
+    }
+}
+
+static NEXT_CLIENT: AtomicU32 = AtomicU32::new(0);
+
+pub struct RpcClient<Q: Copy, P: Copy> {
+    id: u64,
+    next_correlation: u64,
+    requests: ShmQueue<RpcRequest<Q>, ShmMap>,
+    replies: ShmQueue<RpcReply<P>, MutableShmMap>,
+}
+
+impl<Q: Copy, P: Copy> RpcClient<Q, P> {
+    pub fn connect(definition: ShmDefinition) -> Result<Self> {
+        // Unique among the processes
+        let id =
+            (std::process::id() as u64) << 32 | NEXT_CLIENT.fetch_add(1, Ordering::Relaxed) as u64;
+        let replies = ShmQueue::create(reply_definition(&definition.name, id, definition.size))?;
+        ShmQueue::open(definition).map(|requests| Self {
+            id,
+            next_correlation: 0,
+            requests,
+            replies,
+        })
+    }
+
+    // Sends the request and waits for its reply, fails with ETIMEDOUT after the timeout.
+    pub fn call(&mut self, request: Q, timeout: Duration) -> Result<P> {
+        let deadline = Instant::now() + timeout;
+        self.next_correlation += 1;
+        let correlation = self.next_correlation;
+        self.requests.push(
+            RpcRequest {
+                client: self.id,
+                correlation,
+                request,
+            },
+            Some(timeout),
+        )?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let reply = self.replies.pop(Some(remaining))?;
+            // Replies of the calls that timed out are dropped
+            if reply.correlation == correlation {
+                return Ok(reply.reply);
+            }
+        }
+    }
+}
+
+impl<Q: Copy, P: Copy> Drop for RpcClient<Q, P> {
+    fn drop(&mut self) {
+        self.replies.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::errno::Errno;
+
+    use crate::common::{
+        rpc::{RpcClient, RpcServer},
+        ShmDefinition,
+    };
+
+    #[test_log::test]
+    fn calls_get_the_reply_to_their_request() {
+        let mut server: RpcServer<u64, u64> =
+            RpcServer::bind(ShmDefinition::new("rpc".to_string(), 1024)).unwrap();
+        let clients: Vec<_> = (0..3)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut client: RpcClient<u64, u64> =
+                        RpcClient::connect(ShmDefinition::new("rpc".to_string(), 1024)).unwrap();
+                    (0..10)
+                        .map(|j| client.call(i * 100 + j, Duration::from_secs(5)).unwrap())
+                        .sum::<u64>()
+                })
+            })
+            .collect();
+
+        for _ in 0..30 {
+            let call = server.receive(Some(Duration::from_secs(5))).unwrap();
+            server.reply(&call, call.request * 2).unwrap();
+        }
+
+        for (i, client) in clients.into_iter().enumerate() {
+            assert_eq!(2 * (i as u64 * 1000 + 45), client.join().unwrap());
+        }
+    }
+
+    #[test_log::test]
+    fn calls_time_out_without_reply() {
+        let mut server: RpcServer<u64, u64> =
+            RpcServer::bind(ShmDefinition::new("silent_rpc".to_string(), 1024)).unwrap();
+        let client = thread::spawn(|| {
+            let mut client: RpcClient<u64, u64> =
+                RpcClient::connect(ShmDefinition::new("silent_rpc".to_string(), 1024)).unwrap();
+            let late = client.call(1, Duration::from_millis(10)).err();
+            (late, client.call(2, Duration::from_secs(5)))
+        });
+
+        let call = server.receive(None).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        server.reply(&call, 1).unwrap();
+        let call = server.receive(None).unwrap();
+        server.reply(&call, 2).unwrap();
+
+        assert_eq!((Some(Errno::ETIMEDOUT), Ok(2)), client.join().unwrap());
+        // The channel of the disconnected client is dropped
+        assert_eq!(Some(Errno::EPIPE), server.reply(&call, 3).err());
+        assert!(server.replies.is_empty());
+    }
+}
@@ -3,8 +3,10 @@ use std::ptr::null_mut;
 
 use log::debug;
 use nix::fcntl::OFlag;
-use nix::sys::mman::{mmap, munmap, shm_open, shm_unlink, MapFlags, ProtFlags};
-use nix::sys::stat::Mode;
+use nix::sys::mman::{
+    mmap, mremap, munmap, shm_open, shm_unlink, MRemapFlags, MapFlags, ProtFlags,
+};
+use nix::sys::stat::{fstat, Mode};
 use nix::unistd::{close, ftruncate};
 use nix::Result;
 
@@ -32,6 +34,10 @@ impl Drop for MutableShmMap {
 }
 
 impl MutableShmMap {
+    pub fn name(&self) -> &str {
+        self.definition.name.as_str()
+    }
+
     pub fn create(definition: ShmDefinition) -> Result<Self> {
         shm_open(
             definition.name.as_str(),
@@ -45,12 +51,12 @@ impl MutableShmMap {
                 ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
             )
             .and_then(|p| {
-                close(fd).and_then(|_| {
+                close(fd).map(|_| {
                     debug!("created mutableshm {}", definition.name);
-                    Ok(Self {
+                    Self {
                         definition,
                         start_ptr: p as *const u8,
-                    })
+                    }
                 })
             })
         })
@@ -61,11 +67,80 @@ impl MutableShmMap {
     }
 }
 
+// Segments whose mapping can follow a size change of the shared memory object.
+pub trait ShmSegment {
+    fn segment_ptr(&self) -> *mut u8;
+
+    fn size(&self) -> usize;
+
+    // Grows the shared memory object if it is smaller than the size, and remaps it.
+    fn resize(&mut self, size: usize) -> Result<()>;
+}
+
+impl ShmSegment for MutableShmMap {
+    fn segment_ptr(&self) -> *mut u8 {
+        self.start_ptr()
+    }
+
+    fn size(&self) -> usize {
+        self.definition.size
+    }
+
+    fn resize(&mut self, size: usize) -> Result<()> {
+        remap(&mut self.definition, &mut self.start_ptr, size)
+    }
+}
+
+impl ShmSegment for ShmMap {
+    fn segment_ptr(&self) -> *mut u8 {
+        self.start_ptr as *mut u8
+    }
+
+    fn size(&self) -> usize {
+        self.definition.size
+    }
+
+    fn resize(&mut self, size: usize) -> Result<()> {
+        remap(&mut self.definition, &mut self.start_ptr, size)
+    }
+}
+
+fn remap(definition: &mut ShmDefinition, start_ptr: &mut *const u8, size: usize) -> Result<()> {
+    let fd = shm_open(
+        definition.name.as_str(),
+        OFlag::O_RDWR,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+    )?;
+    let result = grow(fd, size).and_then(|_| unsafe {
+        mremap(
+            *start_ptr as *mut c_void,
+            definition.size,
+            size,
+            MRemapFlags::MREMAP_MAYMOVE,
+            None,
+        )
+    });
+    close(fd)?;
+    result.map(|p| {
+        debug!("remapped shm {} to {} bytes", definition.name, size);
+        definition.size = size;
+        *start_ptr = p as *const u8;
+    })
+}
+
+// Never shrinks the object, other processes may have mapped more of it.
+fn grow(fd: RawFd, size: usize) -> Result<()> {
+    fstat(fd).and_then(|stat| {
+        if (stat.st_size as usize) < size {
+            ftruncate(fd, size as _)
+        } else {
+            Ok(())
+        }
+    })
+}
+
 fn create_mmap(definition: &ShmDefinition, fd: RawFd, flags: ProtFlags) -> Result<*mut c_void> {
-    match ftruncate(fd, definition.size as _) {
-        Ok(_) => {}
-        Err(e) => return Err(e),
-    };
+    grow(fd, definition.size)?;
 
     unsafe {
         mmap(
@@ -87,6 +162,10 @@ impl Drop for ShmMap {
 }
 
 impl ShmMap {
+    pub fn name(&self) -> &str {
+        self.definition.name.as_str()
+    }
+
     pub fn open(definition: ShmDefinition) -> Result<Self> {
         shm_open(
             definition.name.as_str(),
@@ -100,12 +179,12 @@ impl ShmMap {
                 ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
             )
             .and_then(|p| {
-                close(fd).and_then(|_| {
+                close(fd).map(|_| {
                     debug!("opened shm {}", definition.name);
-                    Ok(Self {
+                    Self {
                         definition,
                         start_ptr: p as *const u8,
-                    })
+                    }
                 })
             })
         })
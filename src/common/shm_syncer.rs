@@ -43,6 +43,6 @@ impl ShmSync<ShmMap> {
     }
 
     pub fn wait(&mut self) {
-        self.condition.wait(&mut self.mutex);
+        self.condition.wait(&self.mutex);
     }
 }
@@ -0,0 +1,183 @@
+use std::io::{ErrorKind, Read, Write};
+use std::mem::size_of;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use linux_futex::{Futex, Shared};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use nix::Result;
+
+use crate::common::reader::ShmReader;
+use crate::common::shm::{MutableShmMap, ShmMap};
+use crate::common::writer::ShmWriter;
+use crate::common::ShmDefinition;
+
+// Sync object of a socket, each side creates the pipe it writes to.
+#[repr(C)]
+struct SocketHeader {
+    // 1 once the connecting side created its pipe.
+    connected: Futex<Shared>,
+}
+
+// Duplex connection made of a pipe in each direction.
+pub struct ShmSocket<T> {
+    _map: T,
+    header: *const SocketHeader,
+    writer: ShmWriter,
+    reader: Option<ShmReader>,
+    // Pipe of the connecting side, only read once it connected
+    peer_definition: Option<ShmDefinition>,
+    // Process expected to connect, reads fail once it is gone before connecting
+    peer: Option<Pid>,
+    nonblocking: bool,
+}
+
+fn pipe_definition(definition: &ShmDefinition, side: usize) -> ShmDefinition {
+    ShmDefinition::new(format!("{}_{}", definition.name, side), definition.size)
+}
+
+fn sync_definition(definition: &ShmDefinition) -> ShmDefinition {
+    ShmDefinition::new(definition.name.clone(), size_of::<SocketHeader>())
+}
+
+impl ShmSocket<MutableShmMap> {
+    // The size of the definition is the size of each pipe.
+    // The pipe is ready before the sync object, connecting sides look for the latter.
+    pub fn create(definition: ShmDefinition) -> Result<Self> {
+        let writer = ShmWriter::open(pipe_definition(&definition, 0))?;
+        let map = MutableShmMap::create(sync_definition(&definition))?;
+        // Created zeroed, the connecting side may already have set connected
+        let header = map.start_ptr() as *const SocketHeader;
+        Ok(Self {
+            _map: map,
+            header,
+            writer,
+            reader: None,
+            peer_definition: Some(pipe_definition(&definition, 1)),
+            peer: None,
+            nonblocking: false,
+        })
+    }
+
+    pub(crate) fn with_peer(mut self, peer: Pid) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+}
+
+impl ShmSocket<ShmMap> {
+    pub fn connect(definition: ShmDefinition) -> Result<Self> {
+        let map = ShmMap::open(sync_definition(&definition))?;
+        let header = map.start_ptr() as *const SocketHeader;
+        let writer = ShmWriter::open(pipe_definition(&definition, 1))?;
+        let reader = ShmReader::open(pipe_definition(&definition, 0))?;
+        let connected = unsafe { &(*header).connected };
+        connected.value.store(1, Ordering::Release);
+        connected.wake(i32::MAX);
+        Ok(Self {
+            _map: map,
+            header,
+            writer,
+            reader: Some(reader),
+            peer_definition: None,
+            peer: None,
+            nonblocking: false,
+        })
+    }
+}
+
+impl<T> ShmSocket<T> {
+    // Reads and writes then fail with WouldBlock instead of waiting for the peer.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+        self.writer.set_nonblocking(nonblocking);
+        if let Some(reader) = &mut self.reader {
+            reader.set_nonblocking(nonblocking);
+        }
+    }
+
+    // The peer reads the end of file once it read all the written bytes.
+    pub fn shutdown(&mut self) {
+        self.writer.close();
+    }
+
+    pub fn write_message(&mut self, message: &[u8]) -> std::io::Result<()> {
+        self.writer.write_message(message)
+    }
+
+    pub fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        self.reader()?.read_message()
+    }
+
+    // Waits for the connecting side before opening its pipe.
+    fn reader(&mut self) -> std::io::Result<&mut ShmReader> {
+        if self.reader.is_none() {
+            let connected = unsafe { &(*self.header).connected };
+            while connected.value.load(Ordering::Acquire) == 0 {
+                if self.nonblocking {
+                    return Err(ErrorKind::WouldBlock.into());
+                }
+                if let Some(peer) = self.peer {
+                    if kill(peer, None) == Err(Errno::ESRCH) {
+                        return Err(ErrorKind::NotConnected.into());
+                    }
+                }
+                // Wakes up regularly to check the peer is still there
+                let _ = connected.wait_for(0, Duration::from_millis(100));
+            }
+            let definition = self.peer_definition.take().unwrap();
+            let mut reader = ShmReader::open(definition)?;
+            reader.set_nonblocking(self.nonblocking);
+            self.reader = Some(reader);
+        }
+        Ok(self.reader.as_mut().unwrap())
+    }
+}
+
+impl<T> Read for ShmSocket<T> {
+    fn read(&mut self, out: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
+        self.reader()?.read(out)
+    }
+}
+
+impl<T> Write for ShmSocket<T> {
+    fn write(&mut self, value: &[u8]) -> std::result::Result<usize, std::io::Error> {
+        self.writer.write(value)
+    }
+
+    fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::thread;
+
+    use crate::common::{socket::ShmSocket, ShmDefinition};
+
+    #[test_log::test]
+    fn requests_and_responses_go_through_the_socket() {
+        let mut server = ShmSocket::create(ShmDefinition::new("socket".to_string(), 1024)).unwrap();
+        let client = thread::spawn(|| {
+            let mut client =
+                ShmSocket::connect(ShmDefinition::new("socket".to_string(), 1024)).unwrap();
+            client.write_all(b"ping\n").unwrap();
+            client.shutdown();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let mut request = String::new();
+        BufReader::new(&mut server).read_line(&mut request).unwrap();
+        server.write_all(b"pong to ").unwrap();
+        server.write_all(request.as_bytes()).unwrap();
+        server.shutdown();
+
+        assert_eq!("pong to ping\n", client.join().unwrap());
+    }
+}
@@ -0,0 +1,1101 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::mem::{align_of, size_of};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr::{addr_of_mut, null_mut};
+use std::rc::Rc;
+use std::slice;
+use std::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::{fstat, Mode};
+use nix::unistd::{close, fsync, read, write};
+use nix::Result;
+
+use crate::common::clock::now;
+use crate::common::notify::NotifyState;
+use crate::common::pthread::{lock_robust, unlock_robust, RobustLock};
+use crate::common::shm::ShmSegment;
+use crate::common::{Counter, PlainRecord, Record};
+
+pub(crate) const SLOT_LIVE: u64 = 1;
+pub(crate) const SLOT_REMOVED: u64 = 2;
+
+// Number of slot updates a single commit can do on a store opened for shared writes.
+pub const JOURNAL_CAPACITY: usize = 64;
+
+pub const MAX_INDEXES: usize = 4;
+const INDEX_NAME_LEN: usize = 32;
+// End of a bucket chain
+const NO_SLOT: usize = usize::MAX;
+// Reused slots remembered in the header, readers further behind index every slot again.
+const RECYCLED_LOG: usize = 64;
+
+// Shared between the owner and the customers at the beginning of the segment.
+#[repr(C)]
+pub(crate) struct StoreHeader {
+    // Store wide sequence lock, odd while a writer is updating records.
+    // The store version is the number of completed updates (sequence / 2).
+    pub sequence: AtomicU64,
+    pub written_records: AtomicUsize,
+    // Bumped every time the segment grows to the new size.
+    pub generation: AtomicU64,
+    pub size: AtomicUsize,
+    // Bumped every time a slot is reused for another key, readers then index the slot again.
+    pub recycled: AtomicU64,
+    // Slot reused at each recycled count, tagged with the count, see recycled_slots.
+    pub recycled_log: [AtomicU64; RECYCLED_LOG],
+    // Secondary indexes, each one has its buckets of slot chains after the journal.
+    pub index_count: usize,
+    pub index_buckets: usize,
+    pub index_names: [[u8; INDEX_NAME_LEN]; MAX_INDEXES],
+    // Only stores opened for shared writes have a journal, the following
+    // fields are unused otherwise.
+    pub journal_capacity: usize,
+    pub lock: RobustLock,
+    pub journal_len: AtomicUsize,
+    // Version of the journaled update, it is not replayed once the store reached it.
+    pub journal_version: AtomicU64,
+    pub journal_written_records: AtomicUsize,
+    pub journal_recycled: AtomicU64,
+    pub notify: NotifyState,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct StoreSlot<R> {
+    pub version: u64,
+    pub state: u64,
+    // CLOCK_MONOTONIC nanoseconds after which the record is considered missing, 0 for never.
+    pub expires_at: u64,
+    // Hashes of the values extracted by the secondary indexes and next slot of their buckets.
+    pub indexed: [u64; MAX_INDEXES],
+    pub links: [usize; MAX_INDEXES],
+    pub record: R,
+}
+
+impl<R> StoreSlot<R> {
+    pub fn is_live(&self, now: u64) -> bool {
+        self.state == SLOT_LIVE && (self.expires_at == 0 || self.expires_at > now)
+    }
+}
+
+// The slots reused between the recycled counts, None once the log no longer has all of them.
+pub(crate) fn recycled_slots(header: &StoreHeader, from: u64, to: u64) -> Option<Vec<usize>> {
+    if to - from > RECYCLED_LOG as u64 {
+        return None;
+    }
+    (from..to)
+        .map(|recycled| {
+            let entry =
+                header.recycled_log[recycled as usize % RECYCLED_LOG].load(Ordering::Relaxed);
+            (entry >> 32 == recycled & 0xffff_ffff).then_some((entry & 0xffff_ffff) as usize)
+        })
+        .collect()
+}
+
+// Slot update recorded before being applied so that it can be replayed if its writer dies.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct JournalEntry<R> {
+    pub slot: usize,
+    pub content: StoreSlot<R>,
+}
+
+fn journal_offset<R>() -> usize {
+    size_of::<StoreHeader>().next_multiple_of(align_of::<JournalEntry<R>>())
+}
+
+pub(crate) fn buckets_offset<R>(journal_capacity: usize) -> usize {
+    (journal_offset::<R>() + journal_capacity * size_of::<JournalEntry<R>>())
+        .next_multiple_of(align_of::<usize>())
+}
+
+pub(crate) fn records_offset<R>(header: &StoreHeader) -> usize {
+    let buckets = header.index_count * header.index_buckets;
+    (buckets_offset::<R>(header.journal_capacity) + buckets * size_of::<usize>())
+        .next_multiple_of(align_of::<StoreSlot<R>>())
+}
+
+pub(crate) fn capacity<R>(size: usize, header: &StoreHeader) -> usize {
+    size.saturating_sub(records_offset::<R>(header)) / size_of::<StoreSlot<R>>()
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// FNV-1a, unlike the std hasher its output does not change with the toolchain
+// that built the processes sharing the store.
+struct IndexHasher(u64);
+
+impl Hasher for IndexHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+// Deterministic so that every process finds the same bucket for a value.
+pub(crate) fn index_hash<V: Hash + ?Sized>(value: &V) -> u64 {
+    let mut hasher = IndexHasher(FNV_OFFSET_BASIS);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub(crate) fn index_position(header: &StoreHeader, name: &str) -> Option<usize> {
+    let name = index_name(name).ok()?;
+    header.index_names[..header.index_count]
+        .iter()
+        .position(|index_name| *index_name == name)
+}
+
+fn index_name(name: &str) -> Result<[u8; INDEX_NAME_LEN]> {
+    if name.len() > INDEX_NAME_LEN {
+        return Err(Errno::EINVAL);
+    }
+    let mut index_name = [0; INDEX_NAME_LEN];
+    index_name[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(index_name)
+}
+
+// Secondary index of a store, only the hashes of the extracted values are kept in the segment.
+// The customers extract the value of the records found again as different values can collide.
+pub struct StoreIndex<R, V> {
+    name: String,
+    extract: Rc<dyn Fn(&R) -> V>,
+}
+
+impl<R, V> StoreIndex<R, V> {
+    pub fn new(name: &str, extract: impl Fn(&R) -> V + 'static) -> Self {
+        StoreIndex {
+            name: name.to_string(),
+            extract: Rc::new(extract),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn extract(&self, record: &R) -> V {
+        (self.extract)(record)
+    }
+}
+
+impl<R, V> Clone for StoreIndex<R, V> {
+    fn clone(&self) -> Self {
+        StoreIndex {
+            name: self.name.clone(),
+            extract: self.extract.clone(),
+        }
+    }
+}
+
+// Indexes of any value type maintained by the writers of a store.
+pub trait SecondaryIndex<R> {
+    fn name(&self) -> &str;
+    fn hash(&self, record: &R) -> u64;
+}
+
+impl<R, V: Hash> SecondaryIndex<R> for StoreIndex<R, V> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn hash(&self, record: &R) -> u64 {
+        index_hash(&self.extract(record))
+    }
+}
+
+const SNAPSHOT_MAGIC: u64 = u64::from_le_bytes(*b"SHMSTORE");
+// Bumped every time the layout of the snapshot files changes.
+const SNAPSHOT_FORMAT: u64 = 1;
+
+// Beginning of a snapshot file, followed by the version, the remaining time to live and the
+// bytes of every live record. The integers are saved in the byte order of the host.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct SnapshotHeader {
+    magic: u64,
+    format: u64,
+    record_size: u64,
+    record_align: u64,
+    version: u64,
+    records: u64,
+}
+
+impl SnapshotHeader {
+    const LEN: usize = 6 * size_of::<u64>();
+
+    fn new<R>(version: u64, records: usize) -> Self {
+        SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            format: SNAPSHOT_FORMAT,
+            record_size: size_of::<R>() as u64,
+            record_align: align_of::<R>() as u64,
+            version,
+            records: records as u64,
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [
+            self.magic,
+            self.format,
+            self.record_size,
+            self.record_align,
+            self.version,
+            self.records,
+        ]
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect()
+    }
+
+    fn from_bytes(bytes: &[u8; Self::LEN]) -> Self {
+        let value = |i: usize| read_u64(&bytes[i * size_of::<u64>()..]);
+        SnapshotHeader {
+            magic: value(0),
+            format: value(1),
+            record_size: value(2),
+            record_align: value(3),
+            version: value(4),
+            records: value(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Put,
+    Remove,
+}
+
+// Event published on the change stream of a store for every put/remove.
+#[derive(Clone, Copy)]
+pub struct StoreChange<R> {
+    pub version: u64,
+    pub kind: ChangeKind,
+    pub record: R,
+}
+
+pub enum StoreOperation<K, R> {
+    Put(R, Option<Duration>),
+    Remove(K),
+}
+
+// Operations committed together by a store writer.
+pub struct StoreBatch<K, R> {
+    pub(crate) operations: Vec<StoreOperation<K, R>>,
+}
+
+impl<K, R> StoreBatch<K, R> {
+    pub fn new() -> Self {
+        StoreBatch {
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn put(mut self, record: R) -> Self {
+        self.operations.push(StoreOperation::Put(record, None));
+        self
+    }
+
+    pub fn put_with_ttl(mut self, record: R, ttl: Duration) -> Self {
+        self.operations.push(StoreOperation::Put(record, Some(ttl)));
+        self
+    }
+
+    pub fn remove(mut self, key: K) -> Self {
+        self.operations.push(StoreOperation::Remove(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+impl<K, R> Default for StoreBatch<K, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) type Changes<R> = Vec<(ChangeKind, R)>;
+
+// Process local view over the records of a store, used by the processes writing to it.
+pub(crate) struct StoreTable<K, R, M> {
+    segment: M,
+    header: *mut StoreHeader,
+    journal: *mut JournalEntry<R>,
+    records: *mut StoreSlot<R>,
+    generation: u64,
+    capacity: usize,
+    recycled: u64,
+    index: HashMap<K, usize>,
+    next_read: usize,
+    // Removed slots, rebuilt when the store was updated by another writer since the last commit.
+    free: BTreeSet<usize>,
+    free_version: Option<u64>,
+    buckets: *mut usize,
+    indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+}
+
+impl<K: Eq + Hash + Clone, R: Record<K>, M: ShmSegment> StoreTable<K, R, M> {
+    pub fn init(
+        mut segment: M,
+        journal_capacity: usize,
+        indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+    ) -> Result<Self> {
+        if indexes.len() > MAX_INDEXES {
+            return Err(Errno::EINVAL);
+        }
+        let mut index_names = [[0; INDEX_NAME_LEN]; MAX_INDEXES];
+        for (i, index) in indexes.iter().enumerate() {
+            index_names[i] = index_name(index.name())?;
+        }
+        let header = segment.segment_ptr() as *mut StoreHeader;
+        let index_buckets = match indexes.len() {
+            0 => 0,
+            _ => (segment.size() / size_of::<StoreSlot<R>>())
+                .max(1)
+                .next_power_of_two(),
+        };
+        unsafe {
+            (*header).journal_capacity = journal_capacity;
+            (*header).index_count = indexes.len();
+            (*header).index_buckets = index_buckets;
+            (*header).index_names = index_names;
+        }
+        // The segment must at least hold the buckets
+        let records_offset = records_offset::<R>(unsafe { &*header });
+        if records_offset > segment.size() {
+            segment.resize(records_offset.next_power_of_two())?;
+        }
+        let header = segment.segment_ptr() as *mut StoreHeader;
+        unsafe {
+            (*header).sequence.store(0, Ordering::Relaxed);
+            (*header).written_records.store(0, Ordering::Relaxed);
+            (*header).generation.store(0, Ordering::Relaxed);
+            (*header).size.store(segment.size(), Ordering::Relaxed);
+            (*header).recycled.store(0, Ordering::Relaxed);
+            (*header).lock.init();
+            (*header).journal_len.store(0, Ordering::Release);
+        };
+        if journal_capacity == 0 {
+            // Held for good by the single writer, customers can then tell when it died
+            lock_robust(unsafe { &(*header).lock });
+        }
+        let mut table = Self::load(segment, indexes)?;
+        table.free_version = Some(0);
+        table.clear_buckets();
+        Ok(table)
+    }
+
+    // The indexes must be the ones the store was initialized with.
+    pub fn load(segment: M, indexes: Vec<Box<dyn SecondaryIndex<R>>>) -> Result<Self> {
+        let mut table = StoreTable {
+            segment,
+            header: null_mut(),
+            journal: null_mut(),
+            records: null_mut(),
+            generation: 0,
+            capacity: 0,
+            recycled: 0,
+            index: HashMap::new(),
+            next_read: 0,
+            free: BTreeSet::new(),
+            free_version: None,
+            buckets: null_mut(),
+            indexes,
+        };
+        table.map_pointers();
+        let header = table.header();
+        if header.index_count != table.indexes.len()
+            || table
+                .indexes
+                .iter()
+                .enumerate()
+                .any(|(i, index)| index_name(index.name()) != Ok(header.index_names[i]))
+        {
+            return Err(Errno::EINVAL);
+        }
+        Ok(table)
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.header().journal_capacity > 0
+    }
+
+    pub fn version(&self) -> u64 {
+        self.header().sequence.load(Ordering::Relaxed) / 2
+    }
+
+    pub fn written_records(&self) -> usize {
+        self.header().written_records.load(Ordering::Relaxed)
+    }
+
+    pub fn slot(&self, slot: usize) -> StoreSlot<R> {
+        unsafe { self.records.add(slot).read_volatile() }
+    }
+
+    pub fn recycled(&self) -> u64 {
+        self.header().recycled.load(Ordering::Relaxed)
+    }
+
+    // The slots reused since the recycled count, None if they have to be indexed again.
+    pub fn recycled_slots(&self, from: u64) -> Option<Vec<usize>> {
+        recycled_slots(self.header(), from, self.recycled())
+    }
+
+    pub fn live_slot(&self, slot: usize) -> Option<StoreSlot<R>> {
+        let slot = self.slot(slot);
+        slot.is_live(now()).then_some(slot)
+    }
+
+    pub fn get_versioned(&self, key: &K) -> Result<(u64, R)> {
+        self.indexed_slot(key)
+            .and_then(|i| self.live_slot(i))
+            .map(|slot| (slot.version, slot.record))
+            .ok_or(Errno::ENOKEY)
+    }
+
+    // The slot of the key, the index may still map the previous key of a reused slot.
+    fn indexed_slot(&self, key: &K) -> Option<usize> {
+        self.index
+            .get(key)
+            .copied()
+            .filter(|i| self.slot(*i).record.key() == *key)
+    }
+
+    // Runs the function holding the write lock of a shared store.
+    pub fn locked<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if !self.is_shared() {
+            return f(self);
+        }
+        let recovered = lock_robust(&self.header().lock);
+        // Also released if the function panics
+        let table = LockedTable(self);
+        if recovered {
+            table.0.recover();
+        }
+        table.0.follow_growth()?;
+        table.0.catch_up();
+        f(table.0)
+    }
+
+    pub fn compare_and_swap(
+        &mut self,
+        key: &K,
+        expected_version: u64,
+        record: R,
+    ) -> Result<(u64, Changes<R>)> {
+        if record.key() != *key {
+            return Err(Errno::EINVAL);
+        }
+        let current_version = self.get_versioned(key).map(|(v, _)| v).unwrap_or(0);
+        if current_version != expected_version {
+            return Err(Errno::ESTALE);
+        }
+        self.commit(StoreBatch::new().put(record))
+    }
+
+    pub fn increment(&mut self, key: &K, delta: i64) -> Result<(u64, Changes<R>)>
+    where
+        R: Counter<K>,
+    {
+        let (_, current) = self.get_versioned(key)?;
+        let incremented = current.increment(delta).ok_or(Errno::ERANGE)?;
+        self.commit(StoreBatch::new().put(incremented))
+    }
+
+    pub fn commit(&mut self, batch: StoreBatch<K, R>) -> Result<(u64, Changes<R>)> {
+        let version = self.version() + 1;
+        let now = now();
+        let mut written_records = self.written_records();
+        let mut recycled = self.recycled();
+        let mut new_keys: HashMap<K, usize> = HashMap::new();
+        let mut reused_keys: Vec<K> = Vec::new();
+        let mut entries: Vec<JournalEntry<R>> = Vec::with_capacity(batch.len());
+        let mut changes = Vec::with_capacity(batch.len());
+        for operation in batch.operations {
+            // Keys whose slot was reused in the batch are missing
+            let find_slot = |key: &K, new_keys: &HashMap<K, usize>, reused_keys: &Vec<K>| {
+                new_keys.get(key).copied().or_else(|| {
+                    self.indexed_slot(key)
+                        .filter(|_| !reused_keys.contains(key))
+                })
+            };
+            match operation {
+                StoreOperation::Put(record, ttl) => {
+                    let key = record.key();
+                    let slot = match find_slot(&key, &new_keys, &reused_keys) {
+                        Some(slot) => slot,
+                        None if written_records < self.capacity => {
+                            written_records += 1;
+                            written_records - 1
+                        }
+                        None => match self.free_slot(&entries, now) {
+                            Some(slot) => {
+                                let current = self.slot(slot);
+                                if current.state == SLOT_LIVE {
+                                    // Reclaims the expired record
+                                    let content = StoreSlot {
+                                        version,
+                                        state: SLOT_REMOVED,
+                                        ..current
+                                    };
+                                    entries.push(JournalEntry { slot, content });
+                                    changes.push((ChangeKind::Remove, current.record));
+                                }
+                                reused_keys.push(current.record.key());
+                                // Written before the update, a replay then finds it as well
+                                self.header().recycled_log[recycled as usize % RECYCLED_LOG].store(
+                                    (recycled << 32) | slot as u32 as u64,
+                                    Ordering::Relaxed,
+                                );
+                                recycled += 1;
+                                slot
+                            }
+                            None => {
+                                while written_records >= self.capacity {
+                                    self.grow()?;
+                                }
+                                written_records += 1;
+                                written_records - 1
+                            }
+                        },
+                    };
+                    new_keys.insert(key, slot);
+                    let content = StoreSlot {
+                        version,
+                        state: SLOT_LIVE,
+                        expires_at: ttl.map(|ttl| now + ttl.as_nanos() as u64).unwrap_or(0),
+                        indexed: self.index_values(&record),
+                        links: [NO_SLOT; MAX_INDEXES],
+                        record,
+                    };
+                    entries.push(JournalEntry { slot, content });
+                    changes.push((ChangeKind::Put, record));
+                }
+                StoreOperation::Remove(key) => {
+                    let slot = find_slot(&key, &new_keys, &reused_keys).ok_or(Errno::ENOKEY)?;
+                    // The key may have been put earlier in the batch
+                    let current = entries
+                        .iter()
+                        .rev()
+                        .find(|entry| entry.slot == slot)
+                        .map(|entry| entry.content)
+                        .unwrap_or_else(|| self.slot(slot));
+                    // Expired records are still live until they are reclaimed, they can be removed
+                    if current.state != SLOT_LIVE {
+                        return Err(Errno::ENOKEY);
+                    }
+                    let content = StoreSlot {
+                        version,
+                        state: SLOT_REMOVED,
+                        ..current
+                    };
+                    entries.push(JournalEntry { slot, content });
+                    changes.push((ChangeKind::Remove, current.record));
+                }
+            }
+        }
+
+        if self.is_shared() {
+            if entries.len() > self.header().journal_capacity {
+                return Err(Errno::E2BIG);
+            }
+            // The journal is complete once its length is published
+            for (i, entry) in entries.iter().enumerate() {
+                unsafe { self.journal.add(i).write_volatile(*entry) };
+            }
+            self.header()
+                .journal_version
+                .store(version, Ordering::Relaxed);
+            self.header()
+                .journal_written_records
+                .store(written_records, Ordering::Relaxed);
+            self.header()
+                .journal_recycled
+                .store(recycled, Ordering::Relaxed);
+            self.header()
+                .journal_len
+                .store(entries.len(), Ordering::Release);
+        }
+        self.apply(&entries, written_records, recycled);
+        if self.is_shared() {
+            self.header().journal_len.store(0, Ordering::Release);
+        }
+
+        if self.free_version == Some(version - 1) {
+            for entry in &entries {
+                match entry.content.state {
+                    SLOT_LIVE => self.free.remove(&entry.slot),
+                    _ => self.free.insert(entry.slot),
+                };
+            }
+            self.free_version = Some(version);
+        }
+        for key in reused_keys {
+            self.index.remove(&key);
+        }
+        self.index.extend(new_keys);
+        self.next_read = written_records;
+        self.recycled = recycled;
+        Ok((version, changes))
+    }
+
+    // Removes up to limit expired records, returning the version of the update if any.
+    pub fn expire(&mut self, limit: usize) -> Result<Option<(u64, Changes<R>)>> {
+        let now = now();
+        let limit = match self.header().journal_capacity {
+            0 => limit,
+            journal_capacity => journal_capacity.min(limit),
+        };
+        let expired: Vec<K> = (0..self.written_records())
+            .map(|i| self.slot(i))
+            .filter(|slot| slot.state == SLOT_LIVE && !slot.is_live(now))
+            .map(|slot| slot.record.key())
+            .take(limit)
+            .collect();
+        if expired.is_empty() {
+            return Ok(None);
+        }
+        let batch = expired
+            .into_iter()
+            .fold(StoreBatch::new(), |batch, key| batch.remove(key));
+        self.commit(batch).map(Some)
+    }
+
+    // Writes the live records of the current version to the file, returning that version.
+    pub fn snapshot(&self, path: &Path) -> Result<u64>
+    where
+        R: PlainRecord,
+    {
+        let now = now();
+        let slots: Vec<StoreSlot<R>> = (0..self.written_records())
+            .map(|i| self.slot(i))
+            .filter(|slot| slot.is_live(now))
+            .collect();
+        let version = self.version();
+        let mut bytes = SnapshotHeader::new::<R>(version, slots.len()).to_bytes();
+        for slot in slots {
+            bytes.extend_from_slice(&slot.version.to_ne_bytes());
+            let ttl = slot.expires_at.saturating_sub(now);
+            bytes.extend_from_slice(&ttl.to_ne_bytes());
+            bytes.extend_from_slice(record_bytes(&slot.record));
+        }
+        let fd = open(
+            path,
+            OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_WRONLY,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )?;
+        let result = write_all(fd, &bytes).and_then(|_| fsync(fd));
+        close(fd)?;
+        result.map(|_| version)
+    }
+
+    // Loads a snapshot file in a store that was never written, growing it to fit the records.
+    pub fn restore(&mut self, path: &Path) -> Result<u64>
+    where
+        R: PlainRecord,
+    {
+        if self.version() > 0 || self.written_records() > 0 {
+            return Err(Errno::EBUSY);
+        }
+        let fd = open(path, OFlag::O_RDONLY, Mode::empty())?;
+        let result = self.read_snapshot(fd);
+        close(fd)?;
+        result
+    }
+
+    fn read_snapshot(&mut self, fd: RawFd) -> Result<u64>
+    where
+        R: PlainRecord,
+    {
+        let mut bytes = [0; SnapshotHeader::LEN];
+        read_exact(fd, &mut bytes)?;
+        let header = SnapshotHeader::from_bytes(&bytes);
+        if header != SnapshotHeader::new::<R>(header.version, header.records as usize) {
+            return Err(Errno::EINVAL);
+        }
+        let records = header.records as usize;
+        let entry_size = 2 * size_of::<u64>() + size_of::<R>();
+        // The count is checked against the file before allocating anything
+        let len = records.checked_mul(entry_size).ok_or(Errno::EINVAL)?;
+        if Some(fstat(fd)?.st_size as usize) != len.checked_add(SnapshotHeader::LEN) {
+            return Err(Errno::EINVAL);
+        }
+        let mut bytes = vec![0; len];
+        read_exact(fd, &mut bytes)?;
+        while self.capacity < records {
+            self.grow()?;
+        }
+        let slots = bytes.chunks_exact(entry_size).map(|entry| StoreSlot {
+            version: read_u64(entry),
+            state: SLOT_LIVE,
+            expires_at: read_u64(&entry[size_of::<u64>()..]),
+            indexed: [0; MAX_INDEXES],
+            links: [NO_SLOT; MAX_INDEXES],
+            record: unsafe {
+                (entry[2 * size_of::<u64>()..].as_ptr() as *const R).read_unaligned()
+            },
+        });
+
+        let now = now();
+        // Customers attaching early wait for the restored version
+        self.header().sequence.store(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (i, slot) in slots.enumerate() {
+            let expires_at = match slot.expires_at {
+                0 => 0,
+                ttl => now + ttl,
+            };
+            let slot = StoreSlot {
+                expires_at,
+                indexed: self.index_values(&slot.record),
+                ..slot
+            };
+            unsafe { self.records.add(i).write_volatile(slot) };
+            self.link(i, &slot);
+            self.index.insert(slot.record.key(), i);
+        }
+        self.header()
+            .written_records
+            .store(records, Ordering::Relaxed);
+        self.header()
+            .sequence
+            .store(header.version * 2, Ordering::Release);
+        self.next_read = records;
+        self.free.clear();
+        self.free_version = Some(header.version);
+        Ok(header.version)
+    }
+
+    // A removed or expired slot, that is not updated by the pending entries.
+    fn free_slot(&mut self, entries: &[JournalEntry<R>], now: u64) -> Option<usize> {
+        let pending = |slot: &usize| entries.iter().any(|entry| entry.slot == *slot);
+        if self.free_version != Some(self.version()) {
+            self.free = (0..self.written_records())
+                .filter(|i| self.slot(*i).state != SLOT_LIVE)
+                .collect();
+            self.free_version = Some(self.version());
+        }
+        if let Some(slot) = self.free.iter().find(|slot| !pending(slot)) {
+            return Some(*slot);
+        }
+        // The expired records are only looked for once no removed slot is left
+        (0..self.written_records()).find(|i| {
+            let slot = self.slot(*i);
+            slot.state == SLOT_LIVE && !slot.is_live(now) && !pending(i)
+        })
+    }
+
+    // Doubles the size of the segment, customers remap when they see the new generation.
+    fn grow(&mut self) -> Result<()> {
+        let size = self.segment.size() * 2;
+        self.segment.resize(size)?;
+        self.map_pointers();
+        let header = self.header();
+        header.size.store(size, Ordering::Relaxed);
+        self.generation = header.generation.fetch_add(1, Ordering::Release) + 1;
+        Ok(())
+    }
+
+    // Remaps the segment grown by another writer.
+    fn follow_growth(&mut self) -> Result<()> {
+        let generation = self.header().generation.load(Ordering::Acquire);
+        let size = self.header().size.load(Ordering::Relaxed);
+        if generation != self.generation || size != self.segment.size() {
+            self.segment.resize(size)?;
+            self.map_pointers();
+        }
+        Ok(())
+    }
+
+    fn map_pointers(&mut self) {
+        let start_ptr = self.segment.segment_ptr();
+        self.header = start_ptr as *mut StoreHeader;
+        let journal_capacity = self.header().journal_capacity;
+        self.journal = unsafe { start_ptr.add(journal_offset::<R>()) as *mut JournalEntry<R> };
+        self.buckets =
+            unsafe { start_ptr.add(buckets_offset::<R>(journal_capacity)) as *mut usize };
+        self.records = unsafe { start_ptr.add(records_offset::<R>(self.header())) as *mut _ };
+        self.generation = self.header().generation.load(Ordering::Acquire);
+        self.capacity = capacity::<R>(self.segment.size(), self.header());
+    }
+
+    // Indexes the records added by the other writers.
+    fn catch_up(&mut self) {
+        if self.recycled() != self.recycled {
+            match self.recycled_slots(self.recycled) {
+                Some(slots) => {
+                    for i in slots.into_iter().filter(|i| *i < self.next_read) {
+                        self.index.insert(self.slot(i).record.key(), i);
+                    }
+                }
+                None => {
+                    self.index.clear();
+                    self.next_read = 0;
+                }
+            }
+            self.recycled = self.recycled();
+        }
+        let written_records = self.written_records();
+        for i in self.next_read..written_records {
+            self.index.insert(self.slot(i).record.key(), i);
+        }
+        self.next_read = written_records;
+    }
+
+    // Completes the commit of a writer that died while holding the lock.
+    fn recover(&mut self) {
+        let journal_len = self.header().journal_len.load(Ordering::Acquire);
+        // The dead writer may have completed the update before clearing the journal
+        let applied = self.header().journal_version.load(Ordering::Relaxed) <= self.version();
+        if journal_len > 0 && !applied {
+            warn!("replaying {} journal entries of a dead writer", journal_len);
+            // The dead writer may have left the bucket chains half updated
+            self.clear_buckets();
+            for i in 0..self.written_records() {
+                let slot = self.slot(i);
+                if slot.state == SLOT_LIVE {
+                    self.link(i, &slot);
+                }
+            }
+            let entries: Vec<JournalEntry<R>> = (0..journal_len)
+                .map(|i| unsafe { self.journal.add(i).read_volatile() })
+                .collect();
+            let written_records = self
+                .header()
+                .journal_written_records
+                .load(Ordering::Relaxed);
+            let recycled = self.header().journal_recycled.load(Ordering::Relaxed);
+            self.apply(&entries, written_records, recycled);
+        }
+        self.header().journal_len.store(0, Ordering::Release);
+    }
+
+    fn apply(&mut self, entries: &[JournalEntry<R>], written_records: usize, recycled: u64) {
+        let header = self.header();
+        let sequence = header.sequence.load(Ordering::Relaxed);
+        // A dead writer may have already marked the update
+        let sequence = sequence | 1;
+        header.sequence.store(sequence, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for entry in entries {
+            let current = self.slot(entry.slot);
+            if current.state == SLOT_LIVE {
+                self.unlink(entry.slot, &current);
+            }
+            let content = StoreSlot {
+                links: [NO_SLOT; MAX_INDEXES],
+                ..entry.content
+            };
+            unsafe { self.records.add(entry.slot).write_volatile(content) };
+            if content.state == SLOT_LIVE {
+                self.link(entry.slot, &content);
+            }
+        }
+        header
+            .written_records
+            .store(written_records, Ordering::Relaxed);
+        header.recycled.store(recycled, Ordering::Relaxed);
+        header.sequence.store(sequence + 1, Ordering::Release);
+    }
+
+    fn index_values(&self, record: &R) -> [u64; MAX_INDEXES] {
+        let mut values = [0; MAX_INDEXES];
+        for (i, index) in self.indexes.iter().enumerate() {
+            values[i] = index.hash(record);
+        }
+        values
+    }
+
+    fn bucket(&self, index: usize, hash: u64) -> *mut usize {
+        let index_buckets = self.header().index_buckets;
+        unsafe {
+            self.buckets
+                .add(index * index_buckets + (hash as usize & (index_buckets - 1)))
+        }
+    }
+
+    fn clear_buckets(&self) {
+        let header = self.header();
+        for i in 0..header.index_count * header.index_buckets {
+            unsafe { self.buckets.add(i).write_volatile(NO_SLOT) };
+        }
+    }
+
+    // Puts the live slot at the head of its bucket chains.
+    fn link(&self, slot: usize, content: &StoreSlot<R>) {
+        for i in 0..self.indexes.len() {
+            let bucket = self.bucket(i, content.indexed[i]);
+            unsafe {
+                addr_of_mut!((*self.records.add(slot)).links[i])
+                    .write_volatile(bucket.read_volatile());
+                bucket.write_volatile(slot);
+            }
+        }
+    }
+
+    fn unlink(&self, slot: usize, content: &StoreSlot<R>) {
+        for i in 0..self.indexes.len() {
+            let mut link = self.bucket(i, content.indexed[i]);
+            // A chain can not be longer than the number of records
+            for _ in 0..self.written_records() {
+                let next = unsafe { link.read_volatile() };
+                if next == NO_SLOT {
+                    break;
+                }
+                if next == slot {
+                    unsafe { link.write_volatile(content.links[i]) };
+                    break;
+                }
+                link = unsafe { addr_of_mut!((*self.records.add(next)).links[i]) };
+            }
+        }
+    }
+
+    pub(crate) fn notify_state(&self) -> &NotifyState {
+        &self.header().notify
+    }
+
+    fn header(&self) -> &StoreHeader {
+        unsafe { &*self.header }
+    }
+}
+
+// Releases the write lock from the header mapped when done, the segment may have grown.
+struct LockedTable<'a, K: Eq + Hash + Clone, R: Record<K>, M: ShmSegment>(
+    &'a mut StoreTable<K, R, M>,
+);
+
+impl<K: Eq + Hash + Clone, R: Record<K>, M: ShmSegment> Drop for LockedTable<'_, K, R, M> {
+    fn drop(&mut self) {
+        unlock_robust(&self.0.header().lock);
+    }
+}
+
+fn record_bytes<R: PlainRecord>(record: &R) -> &[u8] {
+    unsafe { slice::from_raw_parts(record as *const R as *const u8, size_of::<R>()) }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_ne_bytes(bytes[..size_of::<u64>()].try_into().unwrap())
+}
+
+fn write_all(fd: RawFd, mut bytes: &[u8]) -> Result<()> {
+    while !bytes.is_empty() {
+        let written = write(fd, bytes)?;
+        bytes = &bytes[written..];
+    }
+    Ok(())
+}
+
+// A truncated snapshot is reported as invalid.
+fn read_exact(fd: RawFd, mut bytes: &mut [u8]) -> Result<()> {
+    while !bytes.is_empty() {
+        match read(fd, bytes)? {
+            0 => return Err(Errno::EINVAL),
+            count => bytes = &mut bytes[count..],
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use nix::errno::Errno;
+
+    use crate::common::{
+        shm::ShmMap,
+        store::{JournalEntry, StoreSlot, StoreTable, MAX_INDEXES, SLOT_LIVE},
+        store_customer, store_owner, ShmDefinition, TestRecord,
+    };
+
+    #[test_log::test]
+    fn journal_of_a_dead_writer_is_replayed() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> = store_owner::ShmStore::open_shared(
+            ShmDefinition::new("dead_writer_store".to_string(), 8192),
+        )
+        .unwrap();
+        owner.put(TestRecord { value: (1, 10) }).unwrap();
+
+        // Leave the store as a writer dying in the middle of an update would
+        let map = ShmMap::open(ShmDefinition::new("dead_writer_store".to_string(), 8192)).unwrap();
+        let table: StoreTable<i32, TestRecord, ShmMap> = StoreTable::load(map, Vec::new()).unwrap();
+        let header = table.header();
+        // Owned by a process that had our pid before
+        let hold_lock = || {
+            header
+                .lock
+                .owner
+                .value
+                .store(std::process::id() as i32, Ordering::Relaxed);
+            header.lock.started.store(1, Ordering::Relaxed);
+        };
+        hold_lock();
+        unsafe {
+            table.journal.write(JournalEntry {
+                slot: 0,
+                content: StoreSlot {
+                    version: 2,
+                    state: SLOT_LIVE,
+                    expires_at: 0,
+                    indexed: [0; MAX_INDEXES],
+                    links: [0; MAX_INDEXES],
+                    record: TestRecord { value: (1, 12) },
+                },
+            })
+        };
+        header.journal_version.store(2, Ordering::Relaxed);
+        header.journal_written_records.store(1, Ordering::Relaxed);
+        header.journal_len.store(1, Ordering::Relaxed);
+        header.sequence.store(3, Ordering::Relaxed);
+
+        // Customers do not wait for the update of a dead writer
+        let mut customer: store_customer::ShmStore<i32, TestRecord> =
+            store_customer::ShmStore::open(ShmDefinition::new(
+                "dead_writer_store".to_string(),
+                8192,
+            ))
+            .unwrap();
+        assert_eq!(Some(Errno::EOWNERDEAD), customer.get(&1).err());
+
+        assert_eq!((2, (1, 12)), {
+            let (version, record) = owner.get_versioned(&1).unwrap();
+            (version, record.value)
+        });
+        assert_eq!((1, 12), customer.get(&1).unwrap().value);
+        owner.put(TestRecord { value: (1, 13) }).unwrap();
+        assert_eq!(3, owner.version());
+
+        // A writer dying after the update but before clearing its journal
+        hold_lock();
+        header.journal_version.store(3, Ordering::Relaxed);
+        header.journal_len.store(1, Ordering::Relaxed);
+        owner.put(TestRecord { value: (1, 14) }).unwrap();
+        assert_eq!(4, owner.version());
+        assert_eq!((4, (1, 14)), {
+            let (version, record) = owner.get_versioned(&1).unwrap();
+            (version, record.value)
+        });
+    }
+}
@@ -1,53 +1,751 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
+use std::mem::offset_of;
+use std::ops::RangeBounds;
+use std::ptr::null;
+use std::sync::atomic::{fence, Ordering};
+use std::thread;
 
 use nix::errno::Errno;
 use nix::Result;
 
-use crate::common::shm::ShmMap;
+use crate::common::clock::now;
+use crate::common::notify::ShmNotification;
+use crate::common::pthread::is_abandoned;
+use crate::common::shm::{ShmMap, ShmSegment};
+use crate::common::store::{
+    buckets_offset, capacity, index_hash, index_position, records_offset, recycled_slots,
+    StoreChange, StoreHeader, StoreIndex, StoreSlot,
+};
+use crate::common::stream_consumer::ShmStream;
 use crate::common::{Record, ShmDefinition};
 
+// Spins on an update in progress before checking that its writer is still alive.
+const MAX_SPINS: usize = 1000;
+
 pub struct ShmStore<K, R: Record<K>> {
     map: ShmMap,
-    written_records_ptr: *const usize,
-    end_ptr: *const R,
+    header: *const StoreHeader,
+    buckets: *const usize,
+    records: *const StoreSlot<R>,
+    generation: u64,
+    capacity: usize,
+    recycled: u64,
     next_read: usize,
     index: HashMap<K, usize>,
+    ordered: BTreeMap<K, usize>,
+    ordered_next: usize,
+    ordered_recycled: u64,
 }
 
 impl<K: Eq + Hash + Clone, R: Record<K>> ShmStore<K, R> {
     pub fn open(definition: ShmDefinition) -> Result<Self> {
         ShmMap::open(definition).map(|m| {
-            // We keep the number of written bytes of the beginning
-            let written_records_ptr = m.start_ptr() as *const usize;
-            // Ensure Alignment
-            let end_ptr = unsafe { (m.start_ptr() as *const R).add(1) };
-            Self {
+            let mut store = Self {
                 map: m,
-                written_records_ptr: written_records_ptr,
-                end_ptr: end_ptr,
+                header: null(),
+                buckets: null(),
+                records: null(),
+                generation: 0,
+                capacity: 0,
+                recycled: 0,
                 next_read: 0,
                 index: HashMap::new(),
-            }
+                ordered: BTreeMap::new(),
+                ordered_next: 0,
+                ordered_recycled: 0,
+            };
+            store.map_pointers();
+            store
         })
     }
 
+    // Readable once the store changed, see ShmNotification::clear.
+    pub fn notification(&self) -> Result<ShmNotification> {
+        let name = self.map.name();
+        ShmNotification::register(name, name, offset_of!(StoreHeader, notify))
+    }
+
     pub fn get(&mut self, key: &K) -> Result<R> {
-        let records_count = unsafe { self.written_records_ptr.read_volatile() };
-        if !self.index.contains_key(key) {
-            while self.next_read < records_count {
-                let record = unsafe { self.end_ptr.read_volatile() };
-                self.index.insert(record.key().clone(), self.next_read);
-                self.next_read += 1;
+        self.get_versioned(key).map(|(_, record)| record)
+    }
 
-                unsafe {
-                    self.end_ptr = self.end_ptr.add(1);
-                }
-            }
-        };
+    // The record along with the version of its last update.
+    pub fn get_versioned(&mut self, key: &K) -> Result<(u64, R)> {
+        self.read(|store| store.versioned_record(key))
+            .and_then(|result| result)
+    }
+
+    // Reads all the keys at the same store version.
+    pub fn get_many(&mut self, keys: &[K]) -> Result<Vec<Result<R>>> {
+        self.read(|store| {
+            keys.iter()
+                .map(|key| store.versioned_record(key).map(|(_, record)| record))
+                .collect()
+        })
+    }
+
+    // All the live records along with the store version they reflect. The copy is the read
+    // view of that version, unaffected by the subsequent updates, at the cost of a full read.
+    pub fn snapshot(&mut self) -> Result<(u64, Vec<R>)> {
+        self.read(|store| {
+            let records = (0..store.next_read)
+                .filter_map(|i| store.live_record(i))
+                .collect();
+            (store.version(), records)
+        })
+    }
+
+    pub fn iter(&mut self) -> Result<impl Iterator<Item = R>> {
+        self.snapshot().map(|(_, records)| records.into_iter())
+    }
+
+    // The live records with the given value for the secondary index.
+    pub fn get_by<V: Hash + Eq>(&mut self, index: &StoreIndex<R, V>, value: &V) -> Result<Vec<R>> {
+        let position =
+            index_position(unsafe { &*self.header }, index.name()).ok_or(Errno::EINVAL)?;
+        let hash = index_hash(value);
+        let records = self.read(|store| store.indexed_records(position, hash))?;
+        Ok(records
+            .into_iter()
+            .filter(|record| index.extract(record) == *value)
+            .collect())
+    }
+
+    pub fn version(&self) -> u64 {
+        unsafe { (*self.header).sequence.load(Ordering::Acquire) / 2 }
+    }
+
+    fn versioned_record(&self, key: &K) -> Result<(u64, R)> {
         self.index
             .get(key)
+            .and_then(|i| self.indexed_slot(key, *i))
+            .map(|slot| (slot.version, slot.record))
             .ok_or(Errno::ENOKEY)
-            .and_then(|i| unsafe { Ok(self.end_ptr.sub(records_count - i).read_volatile()) })
+    }
+
+    // The index may still map the previous key of a reused slot.
+    fn indexed_slot(&self, key: &K, slot: usize) -> Option<StoreSlot<R>> {
+        self.live_slot(slot)
+            .filter(|slot| slot.record.key() == *key)
+    }
+
+    fn indexed_records(&self, position: usize, hash: u64) -> Vec<R> {
+        let index_buckets = unsafe { (*self.header).index_buckets };
+        let bucket = position * index_buckets + (hash as usize & (index_buckets - 1));
+        let mut records = Vec::new();
+        let mut next = unsafe { self.buckets.add(bucket).read_volatile() };
+        // The chain may be inconsistent while the owner updates it, read is then retried
+        for _ in 0..self.next_read {
+            if next >= self.next_read {
+                break;
+            }
+            let slot = unsafe { self.records.add(next).read_volatile() };
+            if slot.is_live(now()) && slot.indexed[position] == hash {
+                records.push(slot.record);
+            }
+            next = slot.links[position];
+        }
+        records
+    }
+
+    fn live_record(&self, slot: usize) -> Option<R> {
+        self.live_slot(slot).map(|slot| slot.record)
+    }
+
+    // Expired records are missing even before the owner reclaims them.
+    fn live_slot(&self, slot: usize) -> Option<StoreSlot<R>> {
+        let slot = unsafe { self.records.add(slot).read_volatile() };
+        slot.is_live(now()).then_some(slot)
+    }
+
+    // Runs the function until it observed no concurrent update from the owner.
+    // Fails with EOWNERDEAD while the update of a writer that died is not recovered.
+    fn read<T>(&mut self, f: impl Fn(&Self) -> T) -> Result<T> {
+        let mut spins = 0;
+        loop {
+            let sequence = unsafe { (*self.header).sequence.load(Ordering::Acquire) };
+            if sequence % 2 == 1 {
+                spins += 1;
+                if spins < MAX_SPINS {
+                    std::hint::spin_loop();
+                } else if is_abandoned(unsafe { &(*self.header).lock }) {
+                    return Err(Errno::EOWNERDEAD);
+                } else {
+                    // A long update, or a writer descheduled in the middle of it
+                    thread::yield_now();
+                }
+                continue;
+            }
+            self.follow_growth()?;
+            let recycled = unsafe { (*self.header).recycled.load(Ordering::Relaxed) };
+            // Slots were reused for other keys
+            let reused = (recycled != self.recycled).then(|| self.read_reused_keys(recycled));
+            let new_keys = self.read_new_keys();
+            let result = (reused.is_none() && new_keys.is_empty()).then(|| f(self));
+            fence(Ordering::Acquire);
+            if unsafe { (*self.header).sequence.load(Ordering::Relaxed) } != sequence {
+                continue;
+            }
+            if let Some(result) = result {
+                return Ok(result);
+            }
+            // Keys are only indexed once they were read consistently
+            match reused {
+                Some(Some(reused)) => {
+                    self.index.extend(reused);
+                }
+                Some(None) => {
+                    // Too many slots were reused since, all of them are read again
+                    self.index.clear();
+                    self.next_read = 0;
+                    self.recycled = recycled;
+                    continue;
+                }
+                None => (),
+            }
+            self.recycled = recycled;
+            for key in new_keys {
+                self.index.insert(key, self.next_read);
+                self.next_read += 1;
+            }
+        }
+    }
+
+    fn read_reused_keys(&self, recycled: u64) -> Option<Vec<(K, usize)>> {
+        recycled_slots(unsafe { &*self.header }, self.recycled, recycled).map(|slots| {
+            slots
+                .into_iter()
+                .filter(|i| *i < self.next_read)
+                .map(|i| {
+                    (
+                        unsafe { self.records.add(i).read_volatile() }.record.key(),
+                        i,
+                    )
+                })
+                .collect()
+        })
+    }
+
+    fn read_new_keys(&self) -> Vec<K> {
+        let records_count = unsafe { (*self.header).written_records.load(Ordering::Acquire) };
+        // Records past the mapped capacity are read once the growth is followed
+        (self.next_read..records_count.min(self.capacity))
+            .map(|i| unsafe { self.records.add(i).read_volatile() }.record.key())
+            .collect()
+    }
+
+    // Remaps the segment when the owner grew it.
+    fn follow_growth(&mut self) -> Result<()> {
+        let generation = unsafe { (*self.header).generation.load(Ordering::Acquire) };
+        let size = unsafe { (*self.header).size.load(Ordering::Relaxed) };
+        if generation != self.generation || size != self.map.size() {
+            self.map.resize(size)?;
+            self.map_pointers();
+        }
+        Ok(())
+    }
+
+    fn map_pointers(&mut self) {
+        // We keep the store header at the beginning
+        self.header = self.map.start_ptr() as *const StoreHeader;
+        let header = unsafe { &*self.header };
+        self.buckets = unsafe {
+            self.map
+                .start_ptr()
+                .add(buckets_offset::<R>(header.journal_capacity)) as *const usize
+        };
+        // Ensure Alignment
+        self.records =
+            unsafe { self.map.start_ptr().add(records_offset::<R>(header)) as *const StoreSlot<R> };
+        self.generation = header.generation.load(Ordering::Acquire);
+        self.capacity = capacity::<R>(self.map.size(), header);
+    }
+}
+
+impl<K: Eq + Hash + Clone + Ord, R: Record<K>> ShmStore<K, R> {
+    // The ordered index is only built once a range is first requested.
+    pub fn range(&mut self, range: impl RangeBounds<K>) -> Result<Vec<R>> {
+        loop {
+            // The keys are read under the seqlock like the records
+            let (keys, reset, next_read, recycled) = self.read(|store| {
+                // The slots reused since are ordered again under their new key
+                let reused = recycled_slots(
+                    unsafe { &*store.header },
+                    store.ordered_recycled,
+                    store.recycled,
+                );
+                let reset = reused.is_none();
+                let from = if reset { 0 } else { store.ordered_next };
+                let keys: Vec<(K, usize)> = reused
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|i| *i < from)
+                    .chain(from..store.next_read)
+                    .map(|i| {
+                        (
+                            unsafe { store.records.add(i).read_volatile() }.record.key(),
+                            i,
+                        )
+                    })
+                    .collect();
+                (keys, reset, store.next_read, store.recycled)
+            })?;
+            if reset {
+                self.ordered.clear();
+            }
+            self.ordered.extend(keys);
+            self.ordered_next = next_read;
+            self.ordered_recycled = recycled;
+            // Retry if records were added in between so the result reflects a single version
+            let records = self.read(|store| {
+                (store.ordered_next == store.next_read && store.ordered_recycled == store.recycled)
+                    .then(|| {
+                        store
+                            .ordered
+                            .range((range.start_bound(), range.end_bound()))
+                            .filter_map(|(key, i)| store.indexed_slot(key, *i))
+                            .map(|slot| slot.record)
+                            .collect()
+                    })
+            })?;
+            if let Some(records) = records {
+                return Ok(records);
+            }
+        }
+    }
+}
+
+// Follows the change stream of a store, skipping the changes up to a given version.
+pub struct ShmStoreChanges<R: Copy> {
+    stream: ShmStream<StoreChange<R>>,
+    from_version: u64,
+}
+
+impl<R: Copy> ShmStoreChanges<R> {
+    pub fn open(definition: ShmDefinition, from_version: u64) -> Result<Self> {
+        ShmStream::open(definition).map(|stream| Self {
+            stream,
+            from_version,
+        })
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<StoreChange<R>> {
+        loop {
+            match self.stream.next() {
+                Some(change) if change.version <= self.from_version => continue,
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::hash::{Hash, Hasher};
+    use std::mem::size_of;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::errno::Errno;
+
+    use crate::common::{
+        store::{ChangeKind, StoreBatch, StoreChange, StoreIndex},
+        store_customer::{ShmStore, ShmStoreChanges},
+        store_owner,
+        stream::slots_offset,
+        ShmDefinition, TestRecord,
+    };
+
+    #[test_log::test]
+    fn changes_can_be_followed_from_a_snapshot_version() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("cdc_store".to_string(), 1024)).unwrap();
+        owner
+            .attach_changes(ShmDefinition::new("cdc_changes".to_string(), 1024))
+            .unwrap();
+        owner.put(TestRecord { value: (1, 11) }).unwrap();
+        owner.put(TestRecord { value: (2, 12) }).unwrap();
+
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("cdc_store".to_string(), 1024)).unwrap();
+        let (version, records) = customer.snapshot().unwrap();
+        assert_eq!(2, version);
+        assert_eq!(2, records.len());
+
+        owner.put(TestRecord { value: (1, 21) }).unwrap();
+        owner.remove(&2).unwrap();
+
+        assert_eq!(Some(Errno::ENOKEY), customer.get(&2).err());
+        assert_eq!((1, 21), customer.get(&1).unwrap().value);
+
+        let mut changes: ShmStoreChanges<TestRecord> =
+            ShmStoreChanges::open(ShmDefinition::new("cdc_changes".to_string(), 1024), version)
+                .unwrap();
+        let change = changes.next().unwrap();
+        assert_eq!(
+            (3, ChangeKind::Put, (1, 21)),
+            (change.version, change.kind, change.record.value)
+        );
+        let change = changes.next().unwrap();
+        assert_eq!(
+            (4, ChangeKind::Remove, (2, 12)),
+            (change.version, change.kind, change.record.value)
+        );
+    }
+
+    #[test_log::test]
+    fn range_returns_the_live_records_in_key_order() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("range_store".to_string(), 1024))
+                .unwrap();
+        for key in [5, 1, 4, 2, 3] {
+            owner
+                .put(TestRecord {
+                    value: (key, key * 10),
+                })
+                .unwrap();
+        }
+        owner.remove(&3).unwrap();
+
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("range_store".to_string(), 1024)).unwrap();
+        assert_eq!(4, customer.iter().unwrap().count());
+        let keys: Vec<i32> = customer
+            .range(2..=5)
+            .unwrap()
+            .iter()
+            .map(|r| r.value.0)
+            .collect();
+        assert_eq!(vec![2, 4, 5], keys);
+
+        owner.put(TestRecord { value: (0, 0) }).unwrap();
+        let keys: Vec<i32> = customer
+            .range(..2)
+            .unwrap()
+            .iter()
+            .map(|r| r.value.0)
+            .collect();
+        assert_eq!(vec![0, 1], keys);
+        let keys: Vec<i32> = owner
+            .range(..2)
+            .unwrap()
+            .iter()
+            .map(|r| r.value.0)
+            .collect();
+        assert_eq!(vec![0, 1], keys);
+    }
+
+    #[test_log::test]
+    fn batch_is_committed_under_a_single_version() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("batch_store".to_string(), 1024))
+                .unwrap();
+        owner.put(TestRecord { value: (1, 10) }).unwrap();
+        let (version, _) = owner.get_versioned(&1).unwrap();
+
+        assert_eq!(
+            Some(Errno::ESTALE),
+            owner
+                .compare_and_swap(&1, version + 1, TestRecord { value: (1, 11) })
+                .err()
+        );
+        owner
+            .compare_and_swap(&1, version, TestRecord { value: (1, 11) })
+            .unwrap();
+        assert_eq!((1, 16), owner.increment(&1, 5).unwrap().value);
+        assert_eq!(
+            Some(Errno::ERANGE),
+            owner.increment(&1, i64::from(i32::MAX)).err()
+        );
+        assert_eq!(
+            Some(Errno::ENOKEY),
+            owner
+                .commit(
+                    StoreBatch::new()
+                        .put(TestRecord { value: (2, 20) })
+                        .remove(3)
+                )
+                .err()
+        );
+
+        let version = owner
+            .commit(
+                StoreBatch::new()
+                    .put(TestRecord { value: (2, 20) })
+                    .put(TestRecord { value: (3, 30) })
+                    .remove(1),
+            )
+            .unwrap();
+
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("batch_store".to_string(), 1024)).unwrap();
+        assert_eq!(version, customer.get_versioned(&2).unwrap().0);
+        assert_eq!(version, customer.get_versioned(&3).unwrap().0);
+        let records = customer.get_many(&[1, 2, 3]).unwrap();
+        assert_eq!(Some(Errno::ENOKEY), records[0].err());
+        assert_eq!((3, 30), records[2].unwrap().value);
+    }
+
+    #[test_log::test]
+    fn customers_follow_the_growth_of_the_store() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("growing_store".to_string(), 256))
+                .unwrap();
+        owner.put(TestRecord { value: (0, 0) }).unwrap();
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("growing_store".to_string(), 256)).unwrap();
+        assert_eq!((0, 0), customer.get(&0).unwrap().value);
+
+        for key in 1..100 {
+            owner.put(TestRecord { value: (key, key) }).unwrap();
+        }
+
+        assert_eq!((99, 99), customer.get(&99).unwrap().value);
+        let mut late_customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("growing_store".to_string(), 256)).unwrap();
+        assert_eq!(100, late_customer.iter().unwrap().count());
+    }
+
+    #[test_log::test]
+    fn expired_records_are_missing_and_reclaimed() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("ttl_store".to_string(), 1024)).unwrap();
+        owner
+            .put_with_ttl(TestRecord { value: (1, 10) }, Duration::from_millis(5))
+            .unwrap();
+        owner.put(TestRecord { value: (2, 20) }).unwrap();
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("ttl_store".to_string(), 1024)).unwrap();
+        assert_eq!((1, 10), customer.get(&1).unwrap().value);
+
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(Some(Errno::ENOKEY), customer.get(&1).err());
+        assert_eq!(1, owner.expire().unwrap());
+        assert_eq!(0, owner.expire().unwrap());
+        assert_eq!((2, 20), customer.get(&2).unwrap().value);
+        assert_eq!(1, customer.iter().unwrap().count());
+    }
+
+    #[test_log::test]
+    fn expired_slots_are_reused_before_growing() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("ttl_reuse_store".to_string(), 256))
+                .unwrap();
+        owner
+            .put_with_ttl(TestRecord { value: (0, 0) }, Duration::from_millis(5))
+            .unwrap();
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("ttl_reuse_store".to_string(), 256)).unwrap();
+        assert_eq!((0, 0), customer.get(&0).unwrap().value);
+
+        thread::sleep(Duration::from_millis(10));
+        for key in 1..20 {
+            owner.put(TestRecord { value: (key, key) }).unwrap();
+        }
+
+        assert_eq!(Some(Errno::ENOKEY), customer.get(&0).err());
+        assert_eq!((1, 1), customer.get(&1).unwrap().value);
+        assert_eq!(19, customer.iter().unwrap().count());
+        assert_eq!(0, owner.expire().unwrap());
+    }
+
+    #[test_log::test]
+    fn reused_slots_are_indexed_under_their_new_key() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("reused_store".to_string(), 256))
+                .unwrap();
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("reused_store".to_string(), 256)).unwrap();
+        for key in 0..4 {
+            owner
+                .put_with_ttl(TestRecord { value: (key, key) }, Duration::from_millis(5))
+                .unwrap();
+        }
+        assert_eq!(4, customer.range(0..4).unwrap().len());
+
+        thread::sleep(Duration::from_millis(10));
+        // Expired records can be removed until they are reclaimed
+        assert_eq!((0, 0), owner.remove(&0).unwrap().value);
+        assert_eq!(3, owner.expire().unwrap());
+        for key in 10..14 {
+            owner.put(TestRecord { value: (key, key) }).unwrap();
+        }
+
+        assert_eq!(Some(Errno::ENOKEY), customer.get(&0).err());
+        assert_eq!((12, 12), customer.get(&12).unwrap().value);
+        assert_eq!(
+            vec![10, 11, 12, 13],
+            customer
+                .range(0..20)
+                .unwrap()
+                .iter()
+                .map(|record| record.value.0)
+                .collect::<Vec<i32>>()
+        );
+    }
+
+    #[test_log::test]
+    fn expire_is_bounded_by_the_room_of_the_changes() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("ttl_cdc_store".to_string(), 1024))
+                .unwrap();
+        let room = 4;
+        owner
+            .attach_changes(ShmDefinition::new(
+                "ttl_cdc_changes".to_string(),
+                slots_offset::<StoreChange<TestRecord>>()
+                    + room * size_of::<StoreChange<TestRecord>>(),
+            ))
+            .unwrap();
+        for key in 0..3 {
+            owner
+                .put_with_ttl(TestRecord { value: (key, key) }, Duration::from_millis(5))
+                .unwrap();
+        }
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(1, owner.expire().unwrap());
+        assert_eq!(4, owner.version());
+        assert_eq!(Some(Errno::ENOMEM), owner.expire().err());
+        assert_eq!(4, owner.version());
+
+        owner
+            .attach_changes(ShmDefinition::new("ttl_cdc_rotated".to_string(), 1024))
+            .unwrap();
+        assert_eq!(2, owner.expire().unwrap());
+    }
+
+    // Hashes like its parity
+    #[derive(PartialEq, Eq)]
+    struct Parity(i32);
+
+    impl Hash for Parity {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            (self.0 % 2).hash(state);
+        }
+    }
+
+    fn by_value() -> StoreIndex<TestRecord, i32> {
+        StoreIndex::new("by_value", |record: &TestRecord| record.value.1)
+    }
+
+    fn by_parity() -> StoreIndex<TestRecord, Parity> {
+        StoreIndex::new("by_parity", |record: &TestRecord| Parity(record.value.1))
+    }
+
+    #[test_log::test]
+    fn store_is_restored_from_its_snapshot() {
+        let path = env::temp_dir().join(format!("snapshot_store.{}", std::process::id()));
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("snapshot_store".to_string(), 256))
+                .unwrap();
+        for key in 0..20 {
+            owner.put(TestRecord { value: (key, key) }).unwrap();
+        }
+        owner.remove(&3).unwrap();
+        let version = owner.snapshot(&path).unwrap();
+        drop(owner);
+
+        // Restored in a store of another kind
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open_shared_indexed(
+                ShmDefinition::new("snapshot_store".to_string(), 256),
+                vec![Box::new(by_value())],
+            )
+            .unwrap();
+        assert_eq!(version, owner.restore(&path).unwrap());
+        assert_eq!(Some(Errno::EBUSY), owner.restore(&path).err());
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("snapshot_store".to_string(), 256)).unwrap();
+        assert_eq!(version, customer.version());
+        assert_eq!(19, customer.iter().unwrap().count());
+        assert_eq!(Some(Errno::ENOKEY), customer.get(&3).err());
+        assert_eq!(1, customer.get_by(&by_value(), &5).unwrap().len());
+        owner.put(TestRecord { value: (19, 190) }).unwrap();
+        assert_eq!((19, 190), customer.get(&19).unwrap().value);
+
+        // A corrupt record count is rejected before reading the records
+        let mut bytes = fs::read(&path).unwrap();
+        let mut owner: store_owner::ShmStore<i32, TestRecord> = store_owner::ShmStore::open(
+            ShmDefinition::new("corrupt_snapshot_store".to_string(), 256),
+        )
+        .unwrap();
+        for records in [u64::MAX / 2, 20] {
+            bytes[40..48].copy_from_slice(&records.to_ne_bytes());
+            fs::write(&path, &bytes).unwrap();
+            assert_eq!(Some(Errno::EINVAL), owner.restore(&path).err());
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test_log::test]
+    fn records_are_found_by_their_secondary_index() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open_indexed(
+                ShmDefinition::new("indexed_store".to_string(), 1024),
+                vec![Box::new(by_value()), Box::new(by_parity())],
+            )
+            .unwrap();
+        owner.put(TestRecord { value: (1, 10) }).unwrap();
+        owner.put(TestRecord { value: (2, 10) }).unwrap();
+        owner.put(TestRecord { value: (3, 30) }).unwrap();
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("indexed_store".to_string(), 1024)).unwrap();
+        assert_eq!(2, customer.get_by(&by_value(), &10).unwrap().len());
+
+        owner.put(TestRecord { value: (2, 30) }).unwrap();
+        owner.remove(&3).unwrap();
+
+        let records = customer.get_by(&by_value(), &10).unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!((1, 10), records[0].value);
+        let records = customer.get_by(&by_value(), &30).unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!((2, 30), records[0].value);
+
+        // Values colliding in the index are told apart
+        let records = customer.get_by(&by_parity(), &Parity(10)).unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!((1, 10), records[0].value);
+        assert_eq!(
+            Some(Errno::EINVAL),
+            customer
+                .get_by(
+                    &StoreIndex::new("by_key", |record: &TestRecord| record.value.0),
+                    &1
+                )
+                .err()
+        );
+    }
+
+    #[test_log::test]
+    fn snapshot_keeps_the_version_it_was_taken_at() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("copy_store".to_string(), 1024))
+                .unwrap();
+        owner.put(TestRecord { value: (1, 10) }).unwrap();
+        owner.put(TestRecord { value: (2, 20) }).unwrap();
+        let mut customer: ShmStore<i32, TestRecord> =
+            ShmStore::open(ShmDefinition::new("copy_store".to_string(), 1024)).unwrap();
+        let (version, records) = customer.snapshot().unwrap();
+
+        owner.put(TestRecord { value: (1, 11) }).unwrap();
+        owner.remove(&2).unwrap();
+        owner.put(TestRecord { value: (3, 30) }).unwrap();
+
+        assert_eq!(2, version);
+        assert_eq!(
+            vec![(1, 10), (2, 20)],
+            records
+                .iter()
+                .map(|record| record.value)
+                .collect::<Vec<(i32, i32)>>()
+        );
+        assert_eq!((1, 11), customer.get(&1).unwrap().value);
     }
 }
@@ -1,65 +1,239 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::hash::Hash;
-use std::mem::size_of;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::Duration;
 
 use nix::errno::Errno;
 use nix::Result;
 
+use crate::common::notify::Notifier;
 use crate::common::shm::MutableShmMap;
-use crate::common::{Record, ShmDefinition};
+use crate::common::store::{
+    Changes, SecondaryIndex, StoreBatch, StoreChange, StoreTable, JOURNAL_CAPACITY,
+};
+use crate::common::stream_producer::ShmStream;
+use crate::common::{Counter, PlainRecord, Record, ShmDefinition};
 
 pub struct ShmStore<K, R: Record<K>> {
-    map: MutableShmMap,
-    written_records_ptr: *mut usize,
-    end_ptr: *mut R,
-    available: usize,
-    index: HashMap<K, usize>,
+    table: StoreTable<K, R, MutableShmMap>,
+    ordered: BTreeMap<K, usize>,
+    ordered_next: usize,
+    ordered_recycled: u64,
+    changes: Option<ShmStream<StoreChange<R>>>,
+    notifier: Notifier,
 }
 
-impl<K: Eq + Hash, R: Record<K>> ShmStore<K, R> {
+impl<K: Eq + Hash + Clone, R: Record<K>> ShmStore<K, R> {
     pub fn open(definition: ShmDefinition) -> Result<Self> {
-        let size = definition.size;
-        MutableShmMap::create(definition).map(|m| {
-            // We keep the number of written bytes of the beginning
-            let written_records_ptr = m.start_ptr() as *mut usize;
-            // Ensure Alignment
-            let end_ptr = unsafe { (m.start_ptr() as *mut R).add(1) };
-            unsafe { *written_records_ptr = 0 };
-            Self {
-                map: m,
-                written_records_ptr: written_records_ptr,
-                end_ptr: end_ptr,
-                available: (size - size_of::<u8>()) / size_of::<R>(),
-                index: HashMap::new(),
-            }
+        Self::create(definition, 0, Vec::new())
+    }
+
+    // Allows the processes attaching with store_writer::ShmStore to write as well.
+    pub fn open_shared(definition: ShmDefinition) -> Result<Self> {
+        Self::create(definition, JOURNAL_CAPACITY, Vec::new())
+    }
+
+    // Maintains the secondary indexes that customers query with get_by.
+    pub fn open_indexed(
+        definition: ShmDefinition,
+        indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+    ) -> Result<Self> {
+        Self::create(definition, 0, indexes)
+    }
+
+    pub fn open_shared_indexed(
+        definition: ShmDefinition,
+        indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+    ) -> Result<Self> {
+        Self::create(definition, JOURNAL_CAPACITY, indexes)
+    }
+
+    fn create(
+        definition: ShmDefinition,
+        journal_capacity: usize,
+        indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+    ) -> Result<Self> {
+        let notifier = Notifier::new(&definition.name);
+        MutableShmMap::create(definition).and_then(|m| {
+            // We keep the store header at the beginning
+            StoreTable::init(m, journal_capacity, indexes).map(|table| Self {
+                table,
+                ordered: BTreeMap::new(),
+                ordered_next: 0,
+                ordered_recycled: 0,
+                changes: None,
+                notifier,
+            })
         })
     }
 
+    // Loads a file written by snapshot in a store that was just opened, whatever its kind.
+    // The store is then at the version of the snapshot, which is returned.
+    pub fn restore(&mut self, path: impl AsRef<Path>) -> Result<u64>
+    where
+        R: PlainRecord,
+    {
+        self.table.locked(|table| table.restore(path.as_ref()))
+    }
+
+    // Publishes every subsequent put/remove on a stream created from the definition.
+    // Only available when the owner is the single writer of the store.
+    // The stream is append only: once it is full, every update fails with ENOMEM until
+    // another stream replaces it, that customers follow from the current version.
+    pub fn attach_changes(&mut self, definition: ShmDefinition) -> Result<()> {
+        if self.table.is_shared() {
+            return Err(Errno::ENOTSUP);
+        }
+        ShmStream::open(definition).map(|stream| self.changes = Some(stream))
+    }
+
+    pub fn version(&self) -> u64 {
+        self.table.version()
+    }
+
+    // Writes a consistent copy of the live records to the file, returning its version.
+    pub fn snapshot(&mut self, path: impl AsRef<Path>) -> Result<u64>
+    where
+        R: PlainRecord,
+    {
+        self.table.locked(|table| table.snapshot(path.as_ref()))
+    }
+
+    pub fn iter(&mut self) -> Result<impl Iterator<Item = R>> {
+        self.table
+            .locked(|table| {
+                Ok((0..table.written_records())
+                    .filter_map(|i| table.live_slot(i))
+                    .map(|slot| slot.record)
+                    .collect::<Vec<R>>())
+            })
+            .map(|records| records.into_iter())
+    }
+
+    pub fn get_versioned(&mut self, key: &K) -> Result<(u64, R)> {
+        self.table.locked(|table| table.get_versioned(key))
+    }
+
     pub fn put(&mut self, record: R) -> Result<()> {
-        if self.available > 0 {
-            let key = record.key();
-            let written_records = unsafe { self.written_records_ptr.read_volatile() };
-            match self.index.get(&key) {
-                Some(i) => {
-                    unsafe {
-                        self.end_ptr
-                            .sub(written_records - i + 1)
-                            .write_volatile(record)
-                    };
-                }
-                None => {
-                    unsafe {
-                        self.end_ptr.write(record);
-                        self.written_records_ptr.write_volatile(written_records + 1);
-                        self.end_ptr = self.end_ptr.add(1);
-                        self.index.insert(key, *self.written_records_ptr as usize);
-                    };
-                    self.available -= 1;
-                }
-            };
-            Ok(())
-        } else {
-            Err(Errno::ENOMEM)
+        self.commit(StoreBatch::new().put(record)).map(|_| ())
+    }
+
+    // Customers consider the record missing once the ttl elapsed.
+    pub fn put_with_ttl(&mut self, record: R, ttl: Duration) -> Result<()> {
+        self.commit(StoreBatch::new().put_with_ttl(record, ttl))
+            .map(|_| ())
+    }
+
+    // Expired records that were not reclaimed yet can be removed, as with StoreBatch::remove.
+    pub fn remove(&mut self, key: &K) -> Result<R> {
+        self.ensure_changes_capacity(1)?;
+        let (version, changes) = self
+            .table
+            .locked(|table| table.commit(StoreBatch::new().remove(key.clone())))?;
+        let removed = changes[0].1;
+        self.publish(version, changes).map(|_| removed)
+    }
+
+    // Reclaims the expired records, expired slots are otherwise only reused once the store is full.
+    // Only as many as the change stream has room for are reclaimed, the next calls get the others.
+    pub fn expire(&mut self) -> Result<usize> {
+        self.ensure_changes_capacity(1)?;
+        let limit = match &self.changes {
+            Some(changes) => changes.available(),
+            None => usize::MAX,
+        };
+        match self.table.locked(|table| table.expire(limit))? {
+            Some((version, changes)) => {
+                let expired = changes.len();
+                self.publish(version, changes).map(|_| expired)
+            }
+            None => Ok(0),
         }
     }
+
+    // Puts the record only if the current version of the key is the expected one,
+    // 0 standing for a missing key. Returns the version of the update.
+    pub fn compare_and_swap(&mut self, key: &K, expected_version: u64, record: R) -> Result<u64> {
+        self.ensure_changes_capacity(2)?;
+        let (version, changes) = self
+            .table
+            .locked(|table| table.compare_and_swap(key, expected_version, record))?;
+        self.publish(version, changes).map(|_| version)
+    }
+
+    pub fn increment(&mut self, key: &K, delta: i64) -> Result<R>
+    where
+        R: Counter<K>,
+    {
+        self.ensure_changes_capacity(1)?;
+        let (version, changes) = self.table.locked(|table| table.increment(key, delta))?;
+        let record = changes[0].1;
+        self.publish(version, changes).map(|_| record)
+    }
+
+    // Applies all the operations under a single version so customers see all or none of them.
+    pub fn commit(&mut self, batch: StoreBatch<K, R>) -> Result<u64> {
+        // Each put may also reclaim an expired record
+        self.ensure_changes_capacity(2 * batch.len())?;
+        let (version, changes) = self.table.locked(|table| table.commit(batch))?;
+        self.publish(version, changes).map(|_| version)
+    }
+
+    fn ensure_changes_capacity(&self, count: usize) -> Result<()> {
+        match &self.changes {
+            Some(changes) if changes.available() < count => Err(Errno::ENOMEM),
+            _ => Ok(()),
+        }
+    }
+
+    fn publish(&mut self, version: u64, changes: Changes<R>) -> Result<()> {
+        self.notifier.notify(self.table.notify_state());
+        match &mut self.changes {
+            Some(stream) => changes.into_iter().try_for_each(|(kind, record)| {
+                stream.insert(StoreChange {
+                    version,
+                    kind,
+                    record,
+                })
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Ord, R: Record<K>> ShmStore<K, R> {
+    // The ordered index is only built once a range is first requested.
+    pub fn range(&mut self, range: impl RangeBounds<K>) -> Result<Vec<R>> {
+        let ordered = &mut self.ordered;
+        let ordered_next = &mut self.ordered_next;
+        let ordered_recycled = &mut self.ordered_recycled;
+        self.table.locked(|table| {
+            if table.recycled() != *ordered_recycled {
+                match table.recycled_slots(*ordered_recycled) {
+                    Some(slots) => {
+                        for i in slots.into_iter().filter(|i| *i < *ordered_next) {
+                            ordered.insert(table.slot(i).record.key(), i);
+                        }
+                    }
+                    None => {
+                        ordered.clear();
+                        *ordered_next = 0;
+                    }
+                }
+                *ordered_recycled = table.recycled();
+            }
+            let records_count = table.written_records();
+            for i in *ordered_next..records_count {
+                ordered.insert(table.slot(i).record.key(), i);
+            }
+            *ordered_next = records_count;
+            // Reused slots may still be ordered under their previous key
+            Ok(ordered
+                .range(range)
+                .filter_map(|(key, i)| table.live_slot(*i).filter(|slot| slot.record.key() == *key))
+                .map(|slot| slot.record)
+                .collect())
+        })
+    }
 }
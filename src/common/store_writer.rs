@@ -0,0 +1,141 @@
+use std::hash::Hash;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::notify::Notifier;
+use crate::common::shm::ShmMap;
+use crate::common::store::{SecondaryIndex, StoreBatch, StoreTable};
+use crate::common::{Counter, Record, ShmDefinition};
+
+// Writes to a store opened for shared writes by its owner.
+pub struct ShmStore<K, R: Record<K>> {
+    table: StoreTable<K, R, ShmMap>,
+    notifier: Notifier,
+}
+
+impl<K: Eq + Hash + Clone, R: Record<K>> ShmStore<K, R> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        Self::open_indexed(definition, Vec::new())
+    }
+
+    // The indexes must be the ones the owner opened the store with.
+    pub fn open_indexed(
+        definition: ShmDefinition,
+        indexes: Vec<Box<dyn SecondaryIndex<R>>>,
+    ) -> Result<Self> {
+        let notifier = Notifier::new(&definition.name);
+        ShmMap::open(definition)
+            .and_then(|m| StoreTable::load(m, indexes))
+            .and_then(|table| {
+                if table.is_shared() {
+                    Ok(Self { table, notifier })
+                } else {
+                    Err(Errno::EPERM)
+                }
+            })
+    }
+
+    pub fn version(&self) -> u64 {
+        self.table.version()
+    }
+
+    pub fn get_versioned(&mut self, key: &K) -> Result<(u64, R)> {
+        self.table.locked(|table| table.get_versioned(key))
+    }
+
+    pub fn put(&mut self, record: R) -> Result<()> {
+        self.commit(StoreBatch::new().put(record)).map(|_| ())
+    }
+
+    pub fn put_with_ttl(&mut self, record: R, ttl: Duration) -> Result<()> {
+        self.commit(StoreBatch::new().put_with_ttl(record, ttl))
+            .map(|_| ())
+    }
+
+    // Expired records that were not reclaimed yet can be removed, as with StoreBatch::remove.
+    pub fn remove(&mut self, key: &K) -> Result<R> {
+        self.written(|table| table.commit(StoreBatch::new().remove(key.clone())))
+            .map(|(_, changes)| changes[0].1)
+    }
+
+    pub fn expire(&mut self) -> Result<usize> {
+        self.written(|table| table.expire(usize::MAX))
+            .map(|expired| expired.map(|(_, changes)| changes.len()).unwrap_or(0))
+    }
+
+    pub fn compare_and_swap(&mut self, key: &K, expected_version: u64, record: R) -> Result<u64> {
+        self.written(|table| table.compare_and_swap(key, expected_version, record))
+            .map(|(version, _)| version)
+    }
+
+    pub fn increment(&mut self, key: &K, delta: i64) -> Result<R>
+    where
+        R: Counter<K>,
+    {
+        self.written(|table| table.increment(key, delta))
+            .map(|(_, changes)| changes[0].1)
+    }
+
+    pub fn commit(&mut self, batch: StoreBatch<K, R>) -> Result<u64> {
+        self.written(|table| table.commit(batch))
+            .map(|(version, _)| version)
+    }
+
+    // Notifies the customers of the changes.
+    fn written<T>(
+        &mut self,
+        f: impl FnOnce(&mut StoreTable<K, R, ShmMap>) -> Result<T>,
+    ) -> Result<T> {
+        let result = self.table.locked(f);
+        if result.is_ok() {
+            self.notifier.notify(self.table.notify_state());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::errno::Errno;
+
+    use crate::common::{store_owner, store_writer::ShmStore, ShmDefinition, TestRecord};
+
+    #[test_log::test]
+    fn writers_of_a_shared_store_do_not_lose_updates() {
+        let mut owner: store_owner::ShmStore<i32, TestRecord> = store_owner::ShmStore::open_shared(
+            ShmDefinition::new("shared_store".to_string(), 8192),
+        )
+        .unwrap();
+        owner.put(TestRecord { value: (1, 0) }).unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let mut writer: ShmStore<i32, TestRecord> =
+                        ShmStore::open(ShmDefinition::new("shared_store".to_string(), 8192))
+                            .unwrap();
+                    for _ in 0..100 {
+                        writer.increment(&1, 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!((1, 400), owner.get_versioned(&1).unwrap().1.value);
+    }
+
+    #[test_log::test]
+    fn writers_require_a_shared_store() {
+        let _owner: store_owner::ShmStore<i32, TestRecord> =
+            store_owner::ShmStore::open(ShmDefinition::new("private_store".to_string(), 1024))
+                .unwrap();
+        let writer: nix::Result<ShmStore<i32, TestRecord>> =
+            ShmStore::open(ShmDefinition::new("private_store".to_string(), 1024));
+        assert_eq!(Some(Errno::EPERM), writer.err());
+    }
+}
@@ -0,0 +1,56 @@
+use std::mem::{align_of, size_of};
+
+use crate::common::notify::NotifyState;
+
+// Metadata stamped on each event of an enveloped stream.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope<E> {
+    // Starts at 1 for the first event of the stream
+    pub sequence: u64,
+    // Nanoseconds of the monotonic clock, comparable between the processes of the host
+    pub timestamp: u64,
+    pub producer: u32,
+    pub type_id: u32,
+    pub flags: u32,
+    pub payload: E,
+}
+
+// Events of plain streams come with their sequence only.
+impl<E> Envelope<E> {
+    pub(crate) fn plain(sequence: u64, payload: E) -> Self {
+        Envelope {
+            sequence,
+            timestamp: 0,
+            producer: 0,
+            type_id: 0,
+            flags: 0,
+            payload,
+        }
+    }
+}
+
+#[repr(C)]
+pub(crate) struct StreamHeader {
+    pub sequence_number: u64,
+    pub enveloped: u32,
+    pub type_id: u32,
+    pub notify: NotifyState,
+    // Layout of the events, checked by the consumers.
+    pub event_size: u32,
+    pub event_align: u32,
+}
+
+// Size of the slot of an event, the slots follow the header.
+pub(crate) fn stride<E>(enveloped: bool) -> usize {
+    if enveloped {
+        size_of::<Envelope<E>>()
+    } else {
+        size_of::<E>()
+    }
+}
+
+pub(crate) fn slots_offset<E>() -> usize {
+    let align = align_of::<Envelope<E>>().max(align_of::<E>());
+    size_of::<StreamHeader>().next_multiple_of(align)
+}
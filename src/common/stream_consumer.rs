@@ -1,53 +1,173 @@
+use std::marker::PhantomData;
+use std::mem::{align_of, offset_of, size_of};
+
+use nix::errno::Errno;
 use nix::Result;
 
+use crate::common::notify::ShmNotification;
 use crate::common::shm::ShmMap;
+use crate::common::stream::{slots_offset, stride, Envelope, StreamHeader};
 use crate::common::ShmDefinition;
 
 use super::shm_syncer::ShmSync;
 
 pub struct ShmStream<E: Copy> {
-    map: ShmMap,
+    _map: ShmMap,
     syncer: ShmSync<ShmMap>,
-    sequence_number: *const u64,
-    end_ptr: *const E,
+    header: *const StreamHeader,
+    slots: *const u8,
+    enveloped: bool,
     next_sequence: u64,
+    _event: PhantomData<*const E>,
 }
 
 impl<E: Copy> ShmStream<E> {
+    // Fails with EINVAL when the producer inserts events of another layout.
     pub fn open(definition: ShmDefinition) -> Result<Self> {
+        Self::load(definition, None)
+    }
+
+    // Also fails with EINVAL unless the producer stamps the events with the type id.
+    pub fn open_enveloped(definition: ShmDefinition, type_id: u32) -> Result<Self> {
+        Self::load(definition, Some(type_id))
+    }
+
+    fn load(definition: ShmDefinition, type_id: Option<u32>) -> Result<Self> {
+        if definition.size < slots_offset::<E>() {
+            return Err(Errno::EINVAL);
+        }
         let name = definition.name.clone();
         ShmSync::<ShmMap>::load(name).and_then(|syncer| {
-            ShmMap::open(definition).map(|m| {
-                // We keep the number of written bytes of the beginning
-                let sequence_number = m.start_ptr() as *const u64;
-                // Ensure Alignment
-                let end_ptr = unsafe { (m.start_ptr() as *const E).add(1) };
-                Self {
-                    map: m,
-                    syncer: syncer,
-                    sequence_number: sequence_number,
-                    end_ptr: end_ptr,
-                    next_sequence: 1,
+            ShmMap::open(definition).and_then(|m| {
+                // We keep the number of inserted events at the beginning
+                let header = m.start_ptr() as *const StreamHeader;
+                let described = unsafe { &*header };
+                if described.event_size as usize != size_of::<E>()
+                    || described.event_align as usize != align_of::<E>()
+                    || type_id.is_some_and(|type_id| {
+                        described.enveloped == 0 || described.type_id != type_id
+                    })
+                {
+                    return Err(Errno::EINVAL);
                 }
+                Ok(Self {
+                    slots: unsafe { m.start_ptr().add(slots_offset::<E>()) },
+                    enveloped: unsafe { (*header).enveloped } != 0,
+                    _map: m,
+                    syncer,
+                    header,
+                    next_sequence: 1,
+                    _event: PhantomData,
+                })
             })
         })
     }
 
+    // Whether the producer stamps the events, see stream_producer::ShmStream::open_enveloped.
+    pub fn is_enveloped(&self) -> bool {
+        self.enveloped
+    }
+
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<E> {
-        let current_sequence = unsafe { self.sequence_number.read_volatile() };
+        self.next_envelope().map(|envelope| envelope.payload)
+    }
+
+    // Only the sequence is set for the events of a plain stream.
+    pub fn next_envelope(&mut self) -> Option<Envelope<E>> {
+        let current_sequence = self.sequence_number();
         if current_sequence < self.next_sequence {
             self.syncer.wait();
         }
+        self.read_next(current_sequence)
+    }
+
+    // Readable once the producer published, see ShmNotification::clear.
+    pub fn notification(&self) -> Result<ShmNotification> {
+        let name = self._map.name();
+        ShmNotification::register(name, name, offset_of!(StreamHeader, notify))
+    }
+
+    // The next event if it was already inserted.
+    pub fn try_next(&mut self) -> Option<E> {
+        self.try_next_envelope().map(|envelope| envelope.payload)
+    }
+
+    pub fn try_next_envelope(&mut self) -> Option<Envelope<E>> {
+        let current_sequence = self.sequence_number();
+        self.read_next(current_sequence)
+    }
+
+    fn sequence_number(&self) -> u64 {
+        unsafe { std::ptr::addr_of!((*self.header).sequence_number).read_volatile() }
+    }
+
+    fn read_next(&mut self, current_sequence: u64) -> Option<Envelope<E>> {
         if current_sequence >= self.next_sequence {
-            let record = unsafe { self.end_ptr.read_volatile() };
+            let slot = unsafe {
+                self.slots
+                    .add((self.next_sequence - 1) as usize * stride::<E>(self.enveloped))
+            };
+            let envelope = if self.enveloped {
+                unsafe { (slot as *const Envelope<E>).read_volatile() }
+            } else {
+                Envelope::plain(self.next_sequence, unsafe {
+                    (slot as *const E).read_volatile()
+                })
+            };
             self.next_sequence += 1;
-
-            unsafe {
-                self.end_ptr = self.end_ptr.add(1);
-            }
-            Some(record)
+            Some(envelope)
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nix::errno::Errno;
+
+    use crate::common::{stream_consumer, stream_producer, ShmDefinition};
+
+    #[test_log::test]
+    fn enveloped_events_are_stamped_on_insert() {
+        let mut producer: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open_enveloped(
+                ShmDefinition::new("enveloped_stream".to_string(), 1024),
+                7,
+            )
+            .unwrap();
+        producer.insert(11).unwrap();
+        producer.insert_with_flags(12, 1).unwrap();
+
+        let mut consumer: stream_consumer::ShmStream<u64> = stream_consumer::ShmStream::open(
+            ShmDefinition::new("enveloped_stream".to_string(), 1024),
+        )
+        .unwrap();
+        assert!(consumer.is_enveloped());
+        let first = consumer.try_next_envelope().unwrap();
+        let second = consumer.try_next_envelope().unwrap();
+        assert_eq!((1, 11, 0), (first.sequence, first.payload, first.flags));
+        assert_eq!((2, 12, 1), (second.sequence, second.payload, second.flags));
+        assert_eq!((7, std::process::id()), (second.type_id, second.producer));
+        assert!(first.timestamp > 0 && first.timestamp <= second.timestamp);
+        assert_eq!(None, consumer.try_next());
+    }
+
+    #[test_log::test]
+    fn consumers_of_another_event_layout_are_refused() {
+        let _producer: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open_enveloped(
+                ShmDefinition::new("checked_stream".to_string(), 1024),
+                7,
+            )
+            .unwrap();
+        let definition = || ShmDefinition::new("checked_stream".to_string(), 1024);
+
+        let other_type = stream_consumer::ShmStream::<u32>::open(definition());
+        assert_eq!(Some(Errno::EINVAL), other_type.err());
+        let other_id = stream_consumer::ShmStream::<u64>::open_enveloped(definition(), 8);
+        assert_eq!(Some(Errno::EINVAL), other_id.err());
+        assert!(stream_consumer::ShmStream::<u64>::open_enveloped(definition(), 7).is_ok());
+    }
+}
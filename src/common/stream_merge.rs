@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::clock::now;
+use crate::common::poller::ShmPoller;
+use crate::common::stream::Envelope;
+use crate::common::stream_consumer::ShmStream;
+
+// Merges enveloped streams in the order of the timestamps stamped by their producers.
+pub struct ShmMerge<E: Copy> {
+    streams: Vec<ShmStream<E>>,
+    heads: Vec<Option<Envelope<E>>>,
+    lateness: u64,
+    poller: ShmPoller,
+}
+
+impl<E: Copy> ShmMerge<E> {
+    // An event is held back while some stream is empty, until it is older than the lateness.
+    // Events published later than that after an event of another stream come out of order.
+    // Fails with EINVAL if a stream is not enveloped, its events have no timestamp.
+    pub fn open(streams: Vec<ShmStream<E>>, lateness: Duration) -> Result<Self> {
+        if !streams.iter().all(ShmStream::is_enveloped) {
+            return Err(Errno::EINVAL);
+        }
+        let mut poller = ShmPoller::new();
+        for stream in &streams {
+            poller.add(stream.notification()?);
+        }
+        Ok(Self {
+            heads: vec![None; streams.len()],
+            streams,
+            lateness: lateness.as_nanos() as u64,
+            poller,
+        })
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Envelope<E>> {
+        loop {
+            if let Some(envelope) = self.try_next() {
+                return Ok(envelope);
+            }
+            // Wakes up when the earliest event gets older than the lateness
+            let timeout = self.earliest().map(|(_, envelope)| {
+                Duration::from_nanos((envelope.timestamp + self.lateness).saturating_sub(now()))
+            });
+            self.poller.wait(timeout)?;
+        }
+    }
+
+    // The next event if it can already be ordered.
+    pub fn try_next(&mut self) -> Option<Envelope<E>> {
+        for (head, stream) in self.heads.iter_mut().zip(self.streams.iter_mut()) {
+            if head.is_none() {
+                *head = stream.try_next_envelope();
+            }
+        }
+        let (i, earliest) = self.earliest()?;
+        if self.heads.iter().all(Option::is_some) || earliest.timestamp + self.lateness <= now() {
+            self.heads[i] = None;
+            Some(earliest)
+        } else {
+            None
+        }
+    }
+
+    fn earliest(&self) -> Option<(usize, Envelope<E>)> {
+        self.heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.map(|envelope| (i, envelope)))
+            .min_by_key(|(_, envelope)| envelope.timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use nix::errno::Errno;
+
+    use crate::common::{stream_consumer, stream_merge::ShmMerge, stream_producer, ShmDefinition};
+
+    #[test_log::test]
+    fn streams_are_merged_in_timestamp_order() {
+        let mut first: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open_enveloped(
+                ShmDefinition::new("merged_1".to_string(), 1024),
+                0,
+            )
+            .unwrap();
+        let mut second: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open_enveloped(
+                ShmDefinition::new("merged_2".to_string(), 1024),
+                0,
+            )
+            .unwrap();
+        first.insert(1).unwrap();
+        second.insert(2).unwrap();
+        first.insert(3).unwrap();
+
+        let streams = ["merged_1", "merged_2"]
+            .iter()
+            .map(|name| {
+                stream_consumer::ShmStream::open_enveloped(
+                    ShmDefinition::new(name.to_string(), 1024),
+                    0,
+                )
+                .unwrap()
+            })
+            .collect();
+        let mut merge = ShmMerge::open(streams, Duration::from_millis(50)).unwrap();
+        let events: Vec<u64> = (0..3).map(|_| merge.next().unwrap().payload).collect();
+        assert_eq!(vec![1, 2, 3], events);
+
+        // Waits for the lateness in case the first stream publishes an earlier event
+        second.insert(4).unwrap();
+        assert_eq!(None, merge.try_next());
+        let start = Instant::now();
+        assert_eq!(4, merge.next().unwrap().payload);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test_log::test]
+    fn plain_streams_can_not_be_merged() {
+        let _producer: stream_producer::ShmStream<u64> =
+            stream_producer::ShmStream::open(ShmDefinition::new("merged_plain".to_string(), 1024))
+                .unwrap();
+        let stream =
+            stream_consumer::ShmStream::open(ShmDefinition::new("merged_plain".to_string(), 1024))
+                .unwrap();
+        assert_eq!(
+            Some(Errno::EINVAL),
+            ShmMerge::<u64>::open(vec![stream], Duration::ZERO).err()
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::mem::{align_of, size_of};
+use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::shm::{MutableShmMap, ShmMap};
+use crate::common::stream::Envelope;
+use crate::common::{stream_consumer, stream_producer, ShmDefinition};
+
+const MAX_TYPES: usize = 32;
+
+/// Messages sent on a multiplexed stream, identified by their type id.
+///
+/// # Safety
+///
+/// The type must have no padding bytes and every bit pattern must be a valid value of it,
+/// its bytes are copied into the stream and read back by other processes.
+pub unsafe trait MessageType: Copy {
+    const TYPE_ID: u32;
+}
+
+// Types registered by the producer, kept in a segment next to the stream.
+#[repr(C)]
+struct TypeRegistry {
+    count: AtomicU32,
+    entries: [TypeEntry; MAX_TYPES],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct TypeEntry {
+    type_id: u32,
+    size: u32,
+    align: u32,
+}
+
+impl TypeEntry {
+    fn of<T: MessageType>() -> Self {
+        TypeEntry {
+            type_id: T::TYPE_ID,
+            size: size_of::<T>() as u32,
+            align: align_of::<T>() as u32,
+        }
+    }
+}
+
+fn registry_definition(name: &str) -> ShmDefinition {
+    ShmDefinition::new(format!("{}_types", name), size_of::<TypeRegistry>())
+}
+
+// Slot of a message, large enough for every type of the stream.
+#[repr(C, align(8))]
+#[derive(Clone, Copy)]
+pub struct Payload<const N: usize>([u8; N]);
+
+// A message of any of the registered types.
+pub struct Message<const N: usize> {
+    envelope: Envelope<Payload<N>>,
+    // Entry registered by the producer for the type id of the message
+    entry: Option<TypeEntry>,
+}
+
+impl<const N: usize> Message<N> {
+    pub fn type_id(&self) -> u32 {
+        self.envelope.type_id
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.envelope.sequence
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.envelope.timestamp
+    }
+
+    // None unless the producer registered the type with the same layout.
+    pub fn downcast<T: MessageType>(&self) -> Option<T> {
+        (self.entry == Some(TypeEntry::of::<T>()) && size_of::<T>() <= N)
+            .then(|| unsafe { (self.envelope.payload.0.as_ptr() as *const T).read_unaligned() })
+    }
+}
+
+// Producer of a stream mixing the registered message types, each fitting in N bytes.
+pub struct ShmMuxProducer<const N: usize> {
+    stream: stream_producer::ShmStream<Payload<N>>,
+    _registry_map: MutableShmMap,
+    registry: *mut TypeRegistry,
+}
+
+impl<const N: usize> ShmMuxProducer<N> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        let registry_map = MutableShmMap::create(registry_definition(&definition.name))?;
+        let registry = registry_map.start_ptr() as *mut TypeRegistry;
+        unsafe { (*registry).count.store(0, Ordering::Release) };
+        stream_producer::ShmStream::open_enveloped(definition, 0).map(|stream| Self {
+            stream,
+            _registry_map: registry_map,
+            registry,
+        })
+    }
+
+    // Published to the consumers, which can only register the same types.
+    pub fn register<T: MessageType>(&mut self) -> Result<()> {
+        let entry = TypeEntry::of::<T>();
+        if size_of::<T>() > N || align_of::<T>() > align_of::<Payload<N>>() {
+            return Err(Errno::EINVAL);
+        }
+        let registry = unsafe { &mut *self.registry };
+        let count = registry.count.load(Ordering::Relaxed) as usize;
+        match registry.entries[..count]
+            .iter()
+            .find(|registered| registered.type_id == entry.type_id)
+        {
+            Some(registered) if *registered == entry => Ok(()),
+            Some(_) => Err(Errno::EEXIST),
+            None if count == MAX_TYPES => Err(Errno::ENOSPC),
+            None => {
+                registry.entries[count] = entry;
+                registry.count.store(count as u32 + 1, Ordering::Release);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn send<T: MessageType>(&mut self, message: T) -> Result<()> {
+        self.send_with_flags(message, 0)
+    }
+
+    pub fn send_with_flags<T: MessageType>(&mut self, message: T, flags: u32) -> Result<()> {
+        let registry = unsafe { &*self.registry };
+        let count = registry.count.load(Ordering::Relaxed) as usize;
+        if !registry.entries[..count].contains(&TypeEntry::of::<T>()) {
+            return Err(Errno::EINVAL);
+        }
+        let mut payload = Payload([0; N]);
+        // Message types have no padding, all the copied bytes are initialized
+        unsafe {
+            copy_nonoverlapping(
+                &message as *const T as *const u8,
+                payload.0.as_mut_ptr(),
+                size_of::<T>(),
+            )
+        };
+        self.stream.insert_typed(payload, T::TYPE_ID, flags)
+    }
+}
+
+type Handler<const N: usize> = Box<dyn FnMut(&Message<N>)>;
+
+// Consumer of a multiplexed stream, dispatching the messages to the handlers of their type.
+pub struct ShmMuxConsumer<const N: usize> {
+    stream: stream_consumer::ShmStream<Payload<N>>,
+    _registry_map: ShmMap,
+    registry: *const TypeRegistry,
+    handlers: HashMap<u32, Handler<N>>,
+}
+
+impl<const N: usize> ShmMuxConsumer<N> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        let registry_map = ShmMap::open(registry_definition(&definition.name))?;
+        let registry = registry_map.start_ptr() as *const TypeRegistry;
+        stream_consumer::ShmStream::open(definition).map(|stream| Self {
+            stream,
+            _registry_map: registry_map,
+            registry,
+            handlers: HashMap::new(),
+        })
+    }
+
+    // Fails unless the producer registered the same type, with the same layout.
+    pub fn register<T: MessageType>(&self) -> Result<()> {
+        let entry = TypeEntry::of::<T>();
+        match self.registered(entry.type_id) {
+            Some(registered) if registered == entry => Ok(()),
+            Some(_) => Err(Errno::EINVAL),
+            None => Err(Errno::ENOENT),
+        }
+    }
+
+    fn registered(&self, type_id: u32) -> Option<TypeEntry> {
+        let registry = unsafe { &*self.registry };
+        let count = registry.count.load(Ordering::Acquire) as usize;
+        registry.entries[..count]
+            .iter()
+            .find(|registered| registered.type_id == type_id)
+            .copied()
+    }
+
+    fn message(&self, envelope: Envelope<Payload<N>>) -> Message<N> {
+        Message {
+            entry: self.registered(envelope.type_id),
+            envelope,
+        }
+    }
+
+    // Registers the type and calls the handler for each of its messages in dispatch.
+    pub fn on<T: MessageType + 'static>(
+        &mut self,
+        mut handler: impl FnMut(T) + 'static,
+    ) -> Result<()> {
+        self.register::<T>()?;
+        self.handlers.insert(
+            T::TYPE_ID,
+            Box::new(move |message: &Message<N>| {
+                if let Some(message) = message.downcast::<T>() {
+                    handler(message)
+                }
+            }),
+        );
+        Ok(())
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Message<N>> {
+        self.stream
+            .next_envelope()
+            .map(|envelope| self.message(envelope))
+    }
+
+    pub fn try_next(&mut self) -> Option<Message<N>> {
+        self.stream
+            .try_next_envelope()
+            .map(|envelope| self.message(envelope))
+    }
+
+    // Hands the messages already sent to their handlers, skipping the types without one.
+    // Returns the number of messages read.
+    pub fn dispatch(&mut self) -> usize {
+        let mut count = 0;
+        while let Some(message) = self.try_next() {
+            if let Some(handler) = self.handlers.get_mut(&message.type_id()) {
+                handler(&message);
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use nix::errno::Errno;
+
+    use crate::common::stream_mux::{MessageType, ShmMuxConsumer, ShmMuxProducer};
+    use crate::common::ShmDefinition;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Trade {
+        price: f64,
+        quantity: u64,
+    }
+
+    unsafe impl MessageType for Trade {
+        const TYPE_ID: u32 = 1;
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Status(u8);
+
+    unsafe impl MessageType for Status {
+        const TYPE_ID: u32 = 2;
+    }
+
+    #[derive(Clone, Copy)]
+    struct FakeTrade;
+
+    unsafe impl MessageType for FakeTrade {
+        const TYPE_ID: u32 = 1;
+    }
+
+    #[test_log::test]
+    fn messages_are_dispatched_by_type() {
+        let mut producer: ShmMuxProducer<16> =
+            ShmMuxProducer::open(ShmDefinition::new("mux_stream".to_string(), 4096)).unwrap();
+        producer.register::<Trade>().unwrap();
+        producer.register::<Status>().unwrap();
+        assert_eq!(Some(Errno::EEXIST), producer.register::<FakeTrade>().err());
+        producer.send(Status(1)).unwrap();
+        producer
+            .send(Trade {
+                price: 1.5,
+                quantity: 10,
+            })
+            .unwrap();
+        producer.send(Status(2)).unwrap();
+
+        let mut consumer: ShmMuxConsumer<16> =
+            ShmMuxConsumer::open(ShmDefinition::new("mux_stream".to_string(), 4096)).unwrap();
+        assert_eq!(Some(Errno::EINVAL), consumer.register::<FakeTrade>().err());
+        let statuses = Rc::new(RefCell::new(Vec::new()));
+        let received = statuses.clone();
+        consumer
+            .on(move |status: Status| received.borrow_mut().push(status.0))
+            .unwrap();
+
+        assert_eq!(3, consumer.dispatch());
+        assert_eq!(vec![1, 2], *statuses.borrow());
+
+        producer
+            .send(Trade {
+                price: 2.0,
+                quantity: 5,
+            })
+            .unwrap();
+        let message = consumer.try_next().unwrap();
+        assert_eq!(None, message.downcast::<Status>());
+        // Same type id, but not the layout registered by the producer
+        assert!(message.downcast::<FakeTrade>().is_none());
+        assert_eq!(
+            Some(Trade {
+                price: 2.0,
+                quantity: 5
+            }),
+            message.downcast::<Trade>()
+        );
+    }
+}
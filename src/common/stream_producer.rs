@@ -1,52 +1,115 @@
-use std::mem::size_of;
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
 
 use nix::errno::Errno;
 use nix::Result;
 
+use crate::common::clock::now;
+use crate::common::notify::Notifier;
 use crate::common::shm::MutableShmMap;
+use crate::common::stream::{slots_offset, stride, Envelope, StreamHeader};
 use crate::common::ShmDefinition;
 
 use super::shm_syncer::ShmSync;
 
 pub struct ShmStream<E: Copy> {
-    map: MutableShmMap,
+    _map: MutableShmMap,
     syncer: ShmSync<MutableShmMap>,
-    sequence_number: *mut u64,
-    end_ptr: *mut E,
+    header: *mut StreamHeader,
+    slots: *mut u8,
+    enveloped: bool,
+    type_id: u32,
+    producer: u32,
     available: usize,
+    notifier: Notifier,
+    _event: PhantomData<*const E>,
 }
 
 impl<E: Copy> ShmStream<E> {
     pub fn open(definition: ShmDefinition) -> Result<Self> {
+        Self::create(definition, None)
+    }
+
+    // Every event is wrapped in an Envelope stamped on insert.
+    pub fn open_enveloped(definition: ShmDefinition, type_id: u32) -> Result<Self> {
+        Self::create(definition, Some(type_id))
+    }
+
+    fn create(definition: ShmDefinition, type_id: Option<u32>) -> Result<Self> {
         let size = definition.size;
         let name = definition.name.clone();
+        let notifier = Notifier::new(&name);
+        let enveloped = type_id.is_some();
+        if size < slots_offset::<E>() {
+            return Err(Errno::EINVAL);
+        }
         ShmSync::<MutableShmMap>::create(name).and_then(|syncer| {
             MutableShmMap::create(definition).map(|m| {
-                // We keep the number of written bytes of the beginning
-                let sequence_number = m.start_ptr() as *mut u64;
-                // Ensure Alignment
-                let end_ptr = unsafe { (m.start_ptr() as *mut E).add(1) };
-                unsafe { *sequence_number = 0 };
+                // We keep the number of inserted events at the beginning
+                let header = m.start_ptr() as *mut StreamHeader;
+                unsafe {
+                    (*header).sequence_number = 0;
+                    (*header).enveloped = enveloped as u32;
+                    (*header).type_id = type_id.unwrap_or(0);
+                    (*header).event_size = size_of::<E>() as u32;
+                    (*header).event_align = align_of::<E>() as u32;
+                }
                 Self {
-                    map: m,
-                    syncer: syncer,
-                    sequence_number: sequence_number,
-                    end_ptr: end_ptr,
-                    available: (size - size_of::<u64>()) / size_of::<E>(),
+                    slots: unsafe { m.start_ptr().add(slots_offset::<E>()) },
+                    _map: m,
+                    syncer,
+                    header,
+                    enveloped,
+                    type_id: type_id.unwrap_or(0),
+                    producer: std::process::id(),
+                    available: (size - slots_offset::<E>()) / stride::<E>(enveloped),
+                    notifier,
+                    _event: PhantomData,
                 }
             })
         })
     }
 
+    pub fn available(&self) -> usize {
+        self.available
+    }
+
     pub fn insert(&mut self, event: E) -> Result<()> {
+        self.insert_with_flags(event, 0)
+    }
+
+    // The flags are only kept by enveloped streams.
+    pub fn insert_with_flags(&mut self, event: E, flags: u32) -> Result<()> {
+        self.insert_typed(event, self.type_id, flags)
+    }
+
+    // Overrides the type id of the stream for this event.
+    pub(crate) fn insert_typed(&mut self, event: E, type_id: u32, flags: u32) -> Result<()> {
         if self.available > 0 {
-            let sequence_number = unsafe { self.sequence_number.read_volatile() };
+            let sequence_number =
+                unsafe { std::ptr::addr_of!((*self.header).sequence_number).read_volatile() };
+            let slot = unsafe {
+                self.slots
+                    .add(sequence_number as usize * stride::<E>(self.enveloped))
+            };
             unsafe {
-                self.end_ptr.write(event);
-                self.sequence_number.write_volatile(sequence_number + 1);
-                self.end_ptr = self.end_ptr.add(1);
+                if self.enveloped {
+                    (slot as *mut Envelope<E>).write(Envelope {
+                        sequence: sequence_number + 1,
+                        timestamp: now(),
+                        producer: self.producer,
+                        type_id,
+                        flags,
+                        payload: event,
+                    });
+                } else {
+                    (slot as *mut E).write(event);
+                }
+                std::ptr::addr_of_mut!((*self.header).sequence_number)
+                    .write_volatile(sequence_number + 1);
             };
             self.syncer.notify_all();
+            self.notifier.notify(unsafe { &(*self.header).notify });
             self.available -= 1;
             Ok(())
         } else {
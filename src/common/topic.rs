@@ -0,0 +1,69 @@
+use std::mem::{align_of, size_of};
+use std::sync::atomic::{AtomicU32, AtomicU64};
+
+use linux_futex::{Futex, Shared};
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::notify::NotifyState;
+
+pub const MAX_TOPICS: usize = 256;
+pub const TOPIC_NAME_LEN: usize = 32;
+
+// A topic declared by the producer, along with the number of events published to it.
+#[repr(C)]
+pub(crate) struct TopicEntry {
+    pub name: [u8; TOPIC_NAME_LEN],
+    pub sequence: AtomicU64,
+}
+
+// Shared at the beginning of the segment of the topics, followed by the events of all of them.
+#[repr(C)]
+pub(crate) struct TopicsHeader {
+    // Bumped on every publish to wake up the blocked consumers.
+    pub written: Futex<Shared>,
+    // Consumers blocked in next, the producer only wakes them up when there are some.
+    pub waiters: AtomicU32,
+    pub topic_count: AtomicU32,
+    pub sequence_number: AtomicU64,
+    pub notify: NotifyState,
+    // Layout of the segment, checked by the consumers.
+    pub capacity: u64,
+    pub event_size: u32,
+    pub event_align: u32,
+    pub topics: [TopicEntry; MAX_TOPICS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct TopicSlot<E> {
+    pub topic: u32,
+    pub sequence: u64,
+    pub event: E,
+}
+
+// An event along with its topic and its sequence number in the topic, starting at 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopicEvent<E> {
+    pub topic: u32,
+    pub sequence: u64,
+    pub event: E,
+}
+
+pub(crate) fn slots_offset<E>() -> usize {
+    size_of::<TopicsHeader>().next_multiple_of(align_of::<TopicSlot<E>>())
+}
+
+// Number of events the segment holds.
+pub(crate) fn capacity<E>(size: usize) -> usize {
+    (size - slots_offset::<E>()) / size_of::<TopicSlot<E>>()
+}
+
+pub(crate) fn topic_name(name: &str) -> Result<[u8; TOPIC_NAME_LEN]> {
+    if name.is_empty() || name.len() > TOPIC_NAME_LEN {
+        return Err(Errno::EINVAL);
+    }
+    let mut topic_name = [0; TOPIC_NAME_LEN];
+    topic_name[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(topic_name)
+}
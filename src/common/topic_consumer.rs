@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+use std::mem::{align_of, offset_of, size_of};
+use std::sync::atomic::Ordering;
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::notify::ShmNotification;
+use crate::common::shm::ShmMap;
+use crate::common::topic::{
+    capacity, slots_offset, topic_name, TopicEvent, TopicSlot, TopicsHeader, TOPIC_NAME_LEN,
+};
+use crate::common::ShmDefinition;
+
+// Reads the events of the subscribed topics of a segment, in publish order.
+pub struct ShmTopics<E: Copy> {
+    map: ShmMap,
+    header: *const TopicsHeader,
+    slots: *const TopicSlot<E>,
+    subscriptions: HashSet<[u8; TOPIC_NAME_LEN]>,
+    // Whether each of the declared topics is subscribed
+    subscribed: Vec<bool>,
+    next_sequence: u64,
+}
+
+impl<E: Copy> ShmTopics<E> {
+    // Fails with EINVAL unless the producer publishes events of the same layout
+    // in a segment of the same size.
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        if definition.size < slots_offset::<E>() {
+            return Err(Errno::EINVAL);
+        }
+        let capacity = capacity::<E>(definition.size) as u64;
+        ShmMap::open(definition).and_then(|m| {
+            let header = m.start_ptr() as *const TopicsHeader;
+            let described = unsafe { &*header };
+            if described.capacity != capacity
+                || described.event_size as usize != size_of::<E>()
+                || described.event_align as usize != align_of::<E>()
+            {
+                return Err(Errno::EINVAL);
+            }
+            Ok(Self {
+                header,
+                slots: unsafe { m.start_ptr().add(slots_offset::<E>()) } as *const TopicSlot<E>,
+                map: m,
+                subscriptions: HashSet::new(),
+                subscribed: Vec::new(),
+                next_sequence: 0,
+            })
+        })
+    }
+
+    // The topic may be declared by the producer later on.
+    pub fn subscribe(&mut self, name: &str) -> Result<()> {
+        self.subscriptions.insert(topic_name(name)?);
+        self.subscribed.clear();
+        Ok(())
+    }
+
+    // The id of a topic declared by the producer.
+    pub fn topic(&self, name: &str) -> Result<u32> {
+        let name = topic_name(name)?;
+        let header = unsafe { &*self.header };
+        let count = header.topic_count.load(Ordering::Acquire) as usize;
+        header.topics[..count]
+            .iter()
+            .position(|entry| entry.name == name)
+            .map(|topic| topic as u32)
+            .ok_or(Errno::ENOENT)
+    }
+
+    // Blocks until an event of a subscribed topic is published.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> TopicEvent<E> {
+        let header = unsafe { &*self.header };
+        // Registered before reading the counter, so either the producer sees the waiter
+        // or the wait sees the new counter
+        header.waiters.fetch_add(1, Ordering::SeqCst);
+        let event = loop {
+            let written = header.written.value.load(Ordering::SeqCst);
+            if let Some(event) = self.try_next() {
+                break event;
+            }
+            // Woken up or interrupted, the sequence number is checked again
+            let _ = header.written.wait(written);
+        };
+        header.waiters.fetch_sub(1, Ordering::SeqCst);
+        event
+    }
+
+    // The next event of a subscribed topic if it was already published.
+    pub fn try_next(&mut self) -> Option<TopicEvent<E>> {
+        let header = unsafe { &*self.header };
+        let sequence_number = header.sequence_number.load(Ordering::Acquire);
+        while self.next_sequence < sequence_number {
+            let slot = unsafe { self.slots.add(self.next_sequence as usize).read() };
+            self.next_sequence += 1;
+            if self.is_subscribed(slot.topic) {
+                return Some(TopicEvent {
+                    topic: slot.topic,
+                    sequence: slot.sequence,
+                    event: slot.event,
+                });
+            }
+        }
+        None
+    }
+
+    // Readable once the producer published to any topic, see ShmNotification::clear.
+    pub fn notification(&self) -> Result<ShmNotification> {
+        let name = self.map.name();
+        ShmNotification::register(name, name, offset_of!(TopicsHeader, notify))
+    }
+
+    fn is_subscribed(&mut self, topic: u32) -> bool {
+        let header = unsafe { &*self.header };
+        // Catches up with the topics declared since
+        let count = header.topic_count.load(Ordering::Acquire) as usize;
+        for entry in &header.topics[self.subscribed.len()..count] {
+            self.subscribed
+                .push(self.subscriptions.contains(&entry.name));
+        }
+        self.subscribed[topic as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use nix::errno::Errno;
+
+    use crate::common::{topic::TopicEvent, topic_consumer, topic_producer, ShmDefinition};
+
+    #[test_log::test]
+    fn consumers_only_get_their_topics() {
+        let mut producer: topic_producer::ShmTopics<u64> =
+            topic_producer::ShmTopics::open(ShmDefinition::new("topics".to_string(), 32768))
+                .unwrap();
+        let eurusd = producer.topic("EURUSD").unwrap();
+        let usdjpy = producer.topic("USDJPY").unwrap();
+        assert_eq!(eurusd, producer.topic("EURUSD").unwrap());
+
+        let mut consumer: topic_consumer::ShmTopics<u64> =
+            topic_consumer::ShmTopics::open(ShmDefinition::new("topics".to_string(), 32768))
+                .unwrap();
+        consumer.subscribe("EURUSD").unwrap();
+        // Declared after the subscription
+        consumer.subscribe("GBPUSD").unwrap();
+
+        producer.publish(eurusd, 1).unwrap();
+        producer.publish(usdjpy, 2).unwrap();
+        let gbpusd = producer.topic("GBPUSD").unwrap();
+        producer.publish(gbpusd, 3).unwrap();
+        assert_eq!(2, producer.publish(eurusd, 4).unwrap());
+
+        let events: Vec<(u32, u64, u64)> = std::iter::from_fn(|| consumer.try_next())
+            .map(|e| (e.topic, e.sequence, e.event))
+            .collect();
+        assert_eq!(vec![(0, 1, 1), (2, 1, 3), (0, 2, 4)], events);
+        assert_eq!(Ok(gbpusd), consumer.topic("GBPUSD"));
+
+        // Smaller than the header of the segment
+        let truncated =
+            topic_consumer::ShmTopics::<u64>::open(ShmDefinition::new("topics".to_string(), 8));
+        assert_eq!(Some(Errno::EINVAL), truncated.err());
+        // Larger than the segment of the producer
+        let oversized =
+            topic_consumer::ShmTopics::<u64>::open(ShmDefinition::new("topics".to_string(), 65536));
+        assert_eq!(Some(Errno::EINVAL), oversized.err());
+        // Events of another layout
+        let other_layout =
+            topic_consumer::ShmTopics::<u32>::open(ShmDefinition::new("topics".to_string(), 32768));
+        assert_eq!(Some(Errno::EINVAL), other_layout.err());
+    }
+
+    #[test_log::test]
+    fn consumer_blocks_until_its_topic_is_published() {
+        let (subscribed, is_subscribed) = mpsc::channel();
+        let mut producer: topic_producer::ShmTopics<u64> = topic_producer::ShmTopics::open(
+            ShmDefinition::new("blocking_topics".to_string(), 32768),
+        )
+        .unwrap();
+        let consumer = thread::spawn(move || {
+            let mut consumer: topic_consumer::ShmTopics<u64> = topic_consumer::ShmTopics::open(
+                ShmDefinition::new("blocking_topics".to_string(), 32768),
+            )
+            .unwrap();
+            consumer.subscribe("B").unwrap();
+            subscribed.send(()).unwrap();
+            consumer.next()
+        });
+
+        is_subscribed.recv().unwrap();
+        let a = producer.topic("A").unwrap();
+        let b = producer.topic("B").unwrap();
+        producer.publish(a, 1).unwrap();
+        producer.publish(b, 2).unwrap();
+        assert_eq!(
+            TopicEvent {
+                topic: b,
+                sequence: 1,
+                event: 2
+            },
+            consumer.join().unwrap()
+        );
+    }
+}
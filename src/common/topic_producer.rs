@@ -0,0 +1,109 @@
+use std::mem::{align_of, size_of};
+use std::sync::atomic::Ordering;
+
+use nix::errno::Errno;
+use nix::Result;
+
+use crate::common::notify::Notifier;
+use crate::common::shm::MutableShmMap;
+use crate::common::topic::{
+    capacity, slots_offset, topic_name, TopicSlot, TopicsHeader, MAX_TOPICS,
+};
+use crate::common::ShmDefinition;
+
+// Publishes the events of many named topics in a single segment.
+pub struct ShmTopics<E: Copy> {
+    _map: MutableShmMap,
+    header: *mut TopicsHeader,
+    slots: *mut TopicSlot<E>,
+    available: usize,
+    notifier: Notifier,
+}
+
+impl<E: Copy> ShmTopics<E> {
+    pub fn open(definition: ShmDefinition) -> Result<Self> {
+        let size = definition.size;
+        if size < slots_offset::<E>() {
+            return Err(Errno::EINVAL);
+        }
+        let notifier = Notifier::new(&definition.name);
+        MutableShmMap::create(definition).map(|m| {
+            let header = m.start_ptr() as *mut TopicsHeader;
+            unsafe {
+                (*header).written.value.store(0, Ordering::Relaxed);
+                (*header).sequence_number.store(0, Ordering::Relaxed);
+                (*header).capacity = capacity::<E>(size) as u64;
+                (*header).event_size = size_of::<E>() as u32;
+                (*header).event_align = align_of::<E>() as u32;
+                (*header).topic_count.store(0, Ordering::Release);
+            }
+            Self {
+                slots: unsafe { m.start_ptr().add(slots_offset::<E>()) } as *mut TopicSlot<E>,
+                _map: m,
+                header,
+                available: capacity::<E>(size),
+                notifier,
+            }
+        })
+    }
+
+    // Declares the topic if needed and returns its id.
+    pub fn topic(&mut self, name: &str) -> Result<u32> {
+        let name = topic_name(name)?;
+        let header = unsafe { &mut *self.header };
+        let count = header.topic_count.load(Ordering::Relaxed) as usize;
+        if let Some(topic) = header.topics[..count]
+            .iter()
+            .position(|entry| entry.name == name)
+        {
+            return Ok(topic as u32);
+        }
+        if count == MAX_TOPICS {
+            return Err(Errno::ENOSPC);
+        }
+        header.topics[count].name = name;
+        header.topics[count].sequence.store(0, Ordering::Relaxed);
+        header
+            .topic_count
+            .store(count as u32 + 1, Ordering::Release);
+        Ok(count as u32)
+    }
+
+    pub fn available(&self) -> usize {
+        self.available
+    }
+
+    // Returns the sequence number of the event in its topic.
+    pub fn publish(&mut self, topic: u32, event: E) -> Result<u64> {
+        let header = unsafe { &*self.header };
+        if topic >= header.topic_count.load(Ordering::Relaxed) {
+            return Err(Errno::EINVAL);
+        }
+        if self.available == 0 {
+            return Err(Errno::ENOMEM);
+        }
+        let sequence = header.topics[topic as usize]
+            .sequence
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        let sequence_number = header.sequence_number.load(Ordering::Relaxed);
+        unsafe {
+            self.slots.add(sequence_number as usize).write(TopicSlot {
+                topic,
+                sequence,
+                event,
+            })
+        };
+        header
+            .sequence_number
+            .store(sequence_number + 1, Ordering::Release);
+        // Ordered with the registration of the waiters, see topic_consumer::ShmTopics::next
+        header.written.value.fetch_add(1, Ordering::SeqCst);
+        if header.waiters.load(Ordering::SeqCst) > 0 {
+            header.written.wake(i32::MAX);
+        }
+        self.notifier.notify(&header.notify);
+        self.available -= 1;
+        Ok(sequence)
+    }
+}
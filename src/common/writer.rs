@@ -1,53 +1,219 @@
-use std::io::Write;
-use std::mem::size_of;
+use std::io::{ErrorKind, Write};
+use std::mem::{offset_of, size_of};
+use std::sync::atomic::{fence, Ordering};
+use std::time::Duration;
 
+use nix::errno::Errno;
 use nix::Result;
 
+use crate::common::notify::{Notifier, ShmNotification};
+use crate::common::pipe::{consumed_name, copy_in, ring_capacity, ring_ptr, PipeHeader};
 use crate::common::shm::MutableShmMap;
 use crate::common::ShmDefinition;
 
 pub struct ShmWriter {
     map: MutableShmMap,
-    written_bytes_ptr: *mut u8,
-    end_ptr: *mut u8,
-    available: usize,
+    header: *const PipeHeader,
+    ring: *mut u8,
+    capacity: usize,
+    nonblocking: bool,
+    notifier: Notifier,
 }
 
 impl ShmWriter {
     pub fn open(definition: ShmDefinition) -> Result<Self> {
         let size = definition.size;
+        // The segment must hold the header and some bytes
+        if ring_capacity(size) == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let notifier = Notifier::new(&definition.name);
         MutableShmMap::create(definition).map(|m| {
-            // We keep the number of written bytes of the beginning
-            let written_bytes_ptr = m.start_ptr();
-            let end_ptr = unsafe { written_bytes_ptr.add(1) };
-            unsafe { *written_bytes_ptr = 0 };
+            // We keep the write position and the reader cursors at the beginning
+            let header = m.start_ptr() as *const PipeHeader;
+            unsafe {
+                (*header)
+                    .capacity
+                    .store(ring_capacity(size) as u64, Ordering::Relaxed);
+                (*header).write_position.store(0, Ordering::Relaxed);
+                (*header).tail.store(0, Ordering::Relaxed);
+                (*header).lock.init();
+                (*header).cursors_moved.store(0, Ordering::Relaxed);
+                for cursor in &(*header).readers {
+                    cursor.owner.store(0, Ordering::Relaxed);
+                }
+                (*header).written.value.store(0, Ordering::Relaxed);
+                (*header).consumed.value.store(0, Ordering::Relaxed);
+                (*header).closed.store(0, Ordering::Release);
+            }
             Self {
+                ring: ring_ptr(m.start_ptr()),
                 map: m,
-                written_bytes_ptr: written_bytes_ptr,
-                end_ptr: end_ptr,
-                available: size - size_of::<u8>(),
+                header,
+                capacity: ring_capacity(size),
+                nonblocking: false,
+                notifier,
             }
         })
     }
+
+    // Writes then fail with WouldBlock instead of waiting for the reader.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    // Writes without waiting whatever the mode of the writer.
+    #[cfg(feature = "async")]
+    pub(crate) fn write_nonblocking(&mut self, value: &[u8]) -> std::io::Result<usize> {
+        let nonblocking = std::mem::replace(&mut self.nonblocking, true);
+        let result = self.write(value);
+        self.nonblocking = nonblocking;
+        result
+    }
+
+    // Readable once a reader consumed bytes, see ShmNotification::clear.
+    pub fn notification(&self) -> Result<ShmNotification> {
+        let name = self.map.name();
+        ShmNotification::register(
+            &consumed_name(name),
+            name,
+            offset_of!(PipeHeader, consumed_notify),
+        )
+    }
+
+    // The reader gets the end of file once it read all the written bytes.
+    pub fn close(&mut self) {
+        let header = unsafe { &*self.header };
+        if header.closed.swap(1, Ordering::Release) == 0 {
+            Self::notify(header);
+            self.notifier.notify(&header.written_notify);
+        }
+    }
+
+    fn notify(header: &PipeHeader) {
+        header.written.value.fetch_add(1, Ordering::Release);
+        header.written.wake(i32::MAX);
+    }
+
+    // Number of bytes that can be written before the slowest reader catches up.
+    pub fn available(&self) -> usize {
+        let header = unsafe { &*self.header };
+        let tail = header.advance_tail();
+        let write_position = header.write_position.load(Ordering::Relaxed);
+        self.capacity - (write_position - tail) as usize
+    }
+
+    // Publishes the message at once, readers of messages never see part of it.
+    // The message must fit in the ring along with its length.
+    // Blocks like write while the ring has no room for it.
+    pub fn write_message(&mut self, message: &[u8]) -> std::io::Result<()> {
+        let frame_size = size_of::<u32>() + message.len();
+        if frame_size > self.capacity || message.len() > u32::MAX as usize {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        self.wait_available(frame_size)?;
+        let write_position = unsafe { &*self.header }
+            .write_position
+            .load(Ordering::Relaxed);
+        unsafe {
+            copy_in(
+                self.ring,
+                self.capacity,
+                write_position,
+                &(message.len() as u32).to_le_bytes(),
+            );
+            copy_in(
+                self.ring,
+                self.capacity,
+                write_position + size_of::<u32>() as u64,
+                message,
+            );
+        }
+        self.publish(write_position + frame_size as u64);
+        Ok(())
+    }
+
+    // Blocks until the reader freed the size in the ring.
+    fn wait_available(&self, size: usize) -> std::io::Result<usize> {
+        let header = unsafe { &*self.header };
+        loop {
+            let consumed = header.consumed.value.load(Ordering::Acquire);
+            let available = self.available();
+            if available >= size {
+                return Ok(available);
+            }
+            if self.nonblocking {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            header.release_dead_readers();
+            // Wakes up regularly to release the cursors of dead readers
+            let _ = header
+                .consumed
+                .wait_for(consumed, Duration::from_millis(100));
+        }
+    }
+
+    fn publish(&mut self, write_position: u64) {
+        let header = unsafe { &*self.header };
+        header
+            .write_position
+            .store(write_position, Ordering::Release);
+        Self::notify(header);
+        self.notifier.notify(&header.written_notify);
+    }
+}
+
+impl Drop for ShmWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
 }
 
 impl Write for ShmWriter {
+    // Blocks until the reader frees some space in the ring. The bytes are retained while no
+    // reader is registered, a full ring then blocks until one registers and reads, unless the
+    // writer is nonblocking.
     fn write(&mut self, value: &[u8]) -> std::result::Result<usize, std::io::Error> {
-        let writable_size = self.available.min(value.len());
-        if writable_size > 0 {
-            unsafe {
-                self.end_ptr.copy_from(value.as_ptr(), writable_size);
-                self.end_ptr = self.end_ptr.add(writable_size);
-                *self.written_bytes_ptr += writable_size as u8;
-            }
-            self.available = self.available - writable_size;
-            Ok(writable_size)
-        } else {
-            Ok(0)
+        if value.is_empty() {
+            return Ok(0);
         }
+        let writable_size = self.wait_available(1)?.min(value.len());
+        let write_position = unsafe { &*self.header }
+            .write_position
+            .load(Ordering::Relaxed);
+        unsafe {
+            copy_in(
+                self.ring,
+                self.capacity,
+                write_position,
+                &value[..writable_size],
+            )
+        };
+        self.publish(write_position + writable_size as u64);
+        Ok(writable_size)
     }
 
+    // Every write is published to the reader, flush only orders it with the subsequent operations.
     fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
+        fence(Ordering::SeqCst);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use nix::errno::Errno;
+
+    use crate::common::{pipe::PipeHeader, writer::ShmWriter, ShmDefinition};
+
+    #[test_log::test]
+    fn writer_requires_room_for_bytes() {
+        let writer = ShmWriter::open(ShmDefinition::new(
+            "empty_pipe".to_string(),
+            size_of::<PipeHeader>(),
+        ));
+        assert_eq!(Some(Errno::EINVAL), writer.err());
+    }
+}